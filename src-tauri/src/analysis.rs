@@ -0,0 +1,1057 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+// Onset envelope framing. A 256 sample hop gives ~172 envelope frames per
+// second at 44.1kHz, which is fine enough to resolve tempo to well under 1 BPM
+// once the autocorrelation peak is interpolated.
+const FRAME_SIZE: usize = 1024;
+const HOP_SIZE: usize = 256;
+
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+const BPM_STEP: f32 = 0.05;
+// Most beats sit around 120 BPM; the prior keeps us from locking onto half or
+// double time when both lags correlate equally well.
+const PRIOR_CENTER_BPM: f32 = 120.0;
+const PRIOR_WIDTH_OCTAVES: f32 = 1.0;
+// Number of beat multiples summed when scoring a candidate tempo.
+const COMB_HARMONICS: usize = 4;
+// Anything shorter than this doesn't contain enough beats to trust.
+const MIN_ANALYSIS_SECONDS: f32 = 4.0;
+
+// Key detection works on a downsampled signal; nothing above a few kHz helps
+// to identify pitch classes. At ~11kHz an 8192 point FFT gives ~1.3Hz bins,
+// enough to separate semitones down to the low bass register.
+const KEY_TARGET_SAMPLE_RATE: u32 = 11025;
+const KEY_FFT_SIZE: usize = 8192;
+const KEY_HOP_SIZE: usize = KEY_FFT_SIZE / 2;
+const KEY_MIN_FREQ: f32 = 55.0;
+const KEY_MAX_FREQ: f32 = 2000.0;
+
+// Krumhansl-Kessler key profiles, starting from the tonic.
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+// Spellings follow the Camelot wheel so every key reads the way DJs expect.
+const MAJOR_NAMES: [&str; 12] = ["C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+const MINOR_NAMES: [&str; 12] = ["C", "C#", "D", "Eb", "E", "F", "F#", "G", "G#", "A", "Bb", "B"];
+
+// Fingerprints follow Haitsma & Kalker: ~370ms frames with 75% overlap, the
+// 300-2000Hz range split into 33 log-spaced bands, and one bit per adjacent
+// band pair for whether the energy difference grew or shrank since the last
+// frame. Frame lengths are set in seconds rather than samples so files at
+// different sample rates still line up.
+const FINGERPRINT_FRAME_SECONDS: f32 = 0.371;
+const FINGERPRINT_BANDS: usize = 33;
+const FINGERPRINT_MIN_FREQ: f32 = 300.0;
+const FINGERPRINT_MAX_FREQ: f32 = 2000.0;
+// Two minutes is plenty to tell tracks apart and keeps the blob small.
+const FINGERPRINT_MAX_SECONDS: f32 = 120.0;
+// Encoder delay and trimmed silence shift re-exports by a few frames at most.
+const FINGERPRINT_MAX_OFFSET: isize = 8;
+const FINGERPRINT_MIN_OVERLAP: usize = 32;
+// Similarity above which two fingerprints are taken to be the same recording.
+pub const FINGERPRINT_MATCH_THRESHOLD: f32 = 0.75;
+
+// EBU R128 / ITU-R BS.1770 gating: 400ms blocks every 100ms, an absolute gate
+// at -70 LUFS and a relative gate 10 LU below the absolute-gated loudness.
+const LOUDNESS_BLOCK_SEGMENTS: usize = 4;
+const LOUDNESS_SEGMENT_SECONDS: f64 = 0.1;
+const LOUDNESS_ABSOLUTE_GATE: f64 = -70.0;
+const LOUDNESS_RELATIVE_GATE: f64 = -10.0;
+// True peak is measured on a 4x oversampled signal, with 12 taps per phase.
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS_PER_PHASE: usize = 12;
+// ReplayGain 2.0 plays everything back at -18 LUFS.
+const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+// Beatbank doesn't store time signatures, so a bar is taken to be four beats.
+pub const BEATS_PER_BAR: usize = 4;
+// How strongly beat tracking prefers steady beat spacing over following
+// onsets, and how far a beat may land from the previous one relative to the
+// expected period.
+const BEAT_TRACKING_TIGHTNESS: f32 = 100.0;
+const BEAT_MIN_SPACING: f32 = 0.5;
+const BEAT_MAX_SPACING: f32 = 2.0;
+// Tracked beats within this RMS distance of a straight line get a constant
+// grid; further off, the tempo drifts and every beat is kept.
+const CONSTANT_GRID_TOLERANCE_SECONDS: f64 = 0.02;
+// Kicks mark the downbeat more reliably than anything else.
+const DOWNBEAT_LOW_PASS: f64 = 150.0;
+
+// Interleaved PCM decoded from an audio file.
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+impl DecodedAudio {
+    pub fn to_mono(&self) -> Vec<f32> {
+        self.samples
+            .chunks(self.channels)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub struct TempoEstimate {
+    pub bpm: f32,
+    // 0.0 - 1.0, how strongly the winning tempo stands out from the others.
+    pub confidence: f32,
+}
+
+fn open_format(path: &Path) -> Result<Box<dyn FormatReader>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    Ok(probed.format)
+}
+
+// Decode the whole file into interleaved f32 samples using symphonia.
+pub fn decode_file(path: &Path) -> Result<DecodedAudio, Box<dyn Error>> {
+    let mut format = open_format(path)?;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No supported audio track found")?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+    let mut channels = track.codec_params.channels.map(|c| c.count()).unwrap_or(0);
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame shouldn't sink the whole file, skip it.
+            Err(SymphoniaError::DecodeError(e)) => {
+                eprintln!("Skipping undecodable packet in {}: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        channels = spec.channels.count();
+        let needed = decoded.capacity() * channels;
+        if sample_buf.as_ref().is_none_or(|buf| buf.capacity() < needed) {
+            sample_buf = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        let buf = sample_buf.as_mut().unwrap();
+        buf.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buf.samples());
+    }
+
+    if sample_rate == 0 || channels == 0 {
+        return Err("Could not determine sample rate or channel count".into());
+    }
+
+    Ok(DecodedAudio { samples, channels, sample_rate })
+}
+
+// Length of the file's audio track in seconds. Uses the frame count from the
+// container header when there is one; MP3s without a Xing/Info header don't
+// have it, so their packets are walked (without decoding) and summed instead.
+pub fn probe_duration(path: &Path) -> Result<f64, Box<dyn Error>> {
+    let mut format = open_format(path)?;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No supported audio track found")?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base.ok_or("Track has no time base")?;
+    if let Some(n_frames) = track.codec_params.n_frames {
+        let time = time_base.calc_time(n_frames);
+        return Ok(time.seconds as f64 + time.frac);
+    }
+
+    let mut timestamps = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => timestamps += packet.dur,
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let time = time_base.calc_time(timestamps);
+    Ok(time.seconds as f64 + time.frac)
+}
+
+// Onset strength: the half-wave rectified rise in log energy between frames,
+// with its running mean removed so sustained loud sections don't dominate.
+fn onset_envelope(samples: &[f32]) -> Vec<f32> {
+    if samples.len() < FRAME_SIZE {
+        return Vec::new();
+    }
+    let log_energy: Vec<f32> = (0..=(samples.len() - FRAME_SIZE) / HOP_SIZE)
+        .map(|i| {
+            let frame = &samples[i * HOP_SIZE..i * HOP_SIZE + FRAME_SIZE];
+            let energy = frame.iter().map(|s| s * s).sum::<f32>() / FRAME_SIZE as f32;
+            (1.0 + 1000.0 * energy).ln()
+        })
+        .collect();
+
+    let flux: Vec<f32> = log_energy
+        .windows(2)
+        .map(|w| (w[1] - w[0]).max(0.0))
+        .collect();
+
+    let window = 16;
+    (0..flux.len())
+        .map(|i| {
+            let start = i.saturating_sub(window);
+            let end = (i + window + 1).min(flux.len());
+            let mean = flux[start..end].iter().sum::<f32>() / (end - start) as f32;
+            (flux[i] - mean).max(0.0)
+        })
+        .collect()
+}
+
+fn autocorrelation(envelope: &[f32], max_lag: usize) -> Vec<f32> {
+    (0..=max_lag.min(envelope.len().saturating_sub(1)))
+        .map(|lag| {
+            envelope[lag..]
+                .iter()
+                .zip(envelope)
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / (envelope.len() - lag) as f32
+        })
+        .collect()
+}
+
+// Linear interpolation so candidate tempos don't have to land on whole lags.
+fn sample_at(values: &[f32], position: f32) -> f32 {
+    let index = position.floor() as usize;
+    if index + 1 >= values.len() {
+        return 0.0;
+    }
+    let frac = position - index as f32;
+    values[index] * (1.0 - frac) + values[index + 1] * frac
+}
+
+// Estimate the tempo of a mono signal by autocorrelating its onset envelope
+// and scoring candidate tempos against the autocorrelation at the beat period
+// and its first few multiples.
+pub fn estimate_tempo(samples: &[f32], sample_rate: u32) -> Option<TempoEstimate> {
+    if (samples.len() as f32) < MIN_ANALYSIS_SECONDS * sample_rate as f32 {
+        return None;
+    }
+
+    let envelope = onset_envelope(samples);
+    let frame_rate = sample_rate as f32 / HOP_SIZE as f32;
+    let max_lag = (60.0 * frame_rate / MIN_BPM).ceil() as usize * COMB_HARMONICS + 1;
+    let acf = autocorrelation(&envelope, max_lag);
+    if acf.is_empty() || acf[0] <= f32::EPSILON {
+        return None;
+    }
+
+    let steps = ((MAX_BPM - MIN_BPM) / BPM_STEP) as usize;
+    let scores: Vec<(f32, f32)> = (0..=steps)
+        .map(|step| {
+            let bpm = MIN_BPM + step as f32 * BPM_STEP;
+            let lag = 60.0 * frame_rate / bpm;
+            let score = (1..=COMB_HARMONICS)
+                .map(|k| sample_at(&acf, lag * k as f32) / k as f32)
+                .sum::<f32>();
+            (bpm, score)
+        })
+        .collect();
+
+    let prior = |bpm: f32| {
+        let octaves = (bpm / PRIOR_CENTER_BPM).log2() / PRIOR_WIDTH_OCTAVES;
+        (-0.5 * octaves * octaves).exp()
+    };
+    let &(bpm, best_score) = scores
+        .iter()
+        .max_by(|a, b| (a.1 * prior(a.0)).total_cmp(&(b.1 * prior(b.0))))?;
+
+    let mean_score = scores.iter().map(|s| s.1).sum::<f32>() / scores.len() as f32;
+    let harmonic_sum: f32 = (1..=COMB_HARMONICS).map(|k| 1.0 / k as f32).sum();
+    let ceiling = acf[0] * harmonic_sum;
+    let confidence = if ceiling > mean_score {
+        ((best_score - mean_score) / (ceiling - mean_score)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    Some(TempoEstimate { bpm, confidence })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MusicalKey {
+    // Pitch class of the tonic, C = 0 through B = 11.
+    pub tonic: u8,
+    pub mode: Mode,
+}
+
+impl MusicalKey {
+    // e.g. "A minor", "Db major".
+    pub fn standard(&self) -> String {
+        match self.mode {
+            Mode::Major => format!("{} major", MAJOR_NAMES[self.tonic as usize]),
+            Mode::Minor => format!("{} minor", MINOR_NAMES[self.tonic as usize]),
+        }
+    }
+
+    // e.g. "Am", "Db". This is the form ID3v2 TKEY expects.
+    pub fn short(&self) -> String {
+        match self.mode {
+            Mode::Major => MAJOR_NAMES[self.tonic as usize].to_string(),
+            Mode::Minor => format!("{}m", MINOR_NAMES[self.tonic as usize]),
+        }
+    }
+
+    // e.g. "8A" for A minor, "8B" for C major. Relative keys share a number.
+    pub fn camelot(&self) -> String {
+        match self.mode {
+            Mode::Major => format!("{}B", camelot_number(self.tonic)),
+            Mode::Minor => format!("{}A", camelot_number((self.tonic + 3) % 12)),
+        }
+    }
+
+    // Accepts Camelot ("8A"), standard ("A minor", "Db major") and short
+    // ("Am", "F#", "Ebmin") notations, as found in tags and user edits.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Some(key) = Self::parse_camelot(text) {
+            return Some(key);
+        }
+
+        let mut chars = text.chars();
+        let natural: i32 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let mut rest = chars.as_str();
+        let mut tonic = natural;
+        if let Some(stripped) = rest.strip_prefix('#').or_else(|| rest.strip_prefix('♯')) {
+            tonic += 1;
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix('b').or_else(|| rest.strip_prefix('♭')) {
+            tonic -= 1;
+            rest = stripped;
+        }
+
+        let mode = match rest.trim() {
+            "" | "M" => Mode::Major,
+            suffix => match suffix.to_lowercase().as_str() {
+                "maj" | "major" => Mode::Major,
+                "m" | "min" | "minor" => Mode::Minor,
+                _ => return None,
+            },
+        };
+        Some(MusicalKey { tonic: tonic.rem_euclid(12) as u8, mode })
+    }
+
+    fn parse_camelot(text: &str) -> Option<Self> {
+        let last = text.chars().last()?;
+        let number: u8 = text[..text.len() - last.len_utf8()].parse().ok()?;
+        if !(1..=12).contains(&number) {
+            return None;
+        }
+        // 7 is its own inverse mod 12, which undoes the walk around the circle of fifths.
+        let major_tonic = ((number as u32 + 4) * 7 % 12) as u8;
+        match last.to_ascii_uppercase() {
+            'B' => Some(MusicalKey { tonic: major_tonic, mode: Mode::Major }),
+            'A' => Some(MusicalKey { tonic: (major_tonic + 9) % 12, mode: Mode::Minor }),
+            _ => None,
+        }
+    }
+}
+
+// Position of a major key on the Camelot wheel: C is 8, each step a fifth up.
+fn camelot_number(major_tonic: u8) -> u8 {
+    ((major_tonic as u32 * 7 + 7) % 12 + 1) as u8
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEstimate {
+    pub key: MusicalKey,
+    // Correlation of the track's chroma with the winning key profile.
+    pub confidence: f32,
+}
+
+// Average pitch class energy across the whole track.
+fn chromagram(samples: &[f32], sample_rate: u32) -> [f32; 12] {
+    let factor = (sample_rate / KEY_TARGET_SAMPLE_RATE).max(1) as usize;
+    let downsampled: Vec<f32> = samples
+        .chunks(factor)
+        .map(|chunk| chunk.iter().sum::<f32>() / chunk.len() as f32)
+        .collect();
+    let rate = sample_rate as f32 / factor as f32;
+
+    let bin_classes: Vec<Option<usize>> = (0..KEY_FFT_SIZE / 2)
+        .map(|bin| {
+            let freq = bin as f32 * rate / KEY_FFT_SIZE as f32;
+            if !(KEY_MIN_FREQ..=KEY_MAX_FREQ).contains(&freq) {
+                return None;
+            }
+            let midi = 12.0 * (freq / 440.0).log2() + 69.0;
+            Some((midi.round() as i32).rem_euclid(12) as usize)
+        })
+        .collect();
+
+    let window: Vec<f32> = (0..KEY_FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / KEY_FFT_SIZE as f32).cos())
+        .collect();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(KEY_FFT_SIZE);
+
+    let mut chroma = [0.0f32; 12];
+    let mut buffer = vec![Complex::new(0.0, 0.0); KEY_FFT_SIZE];
+    let mut start = 0;
+    while start + KEY_FFT_SIZE <= downsampled.len() {
+        for (slot, (sample, w)) in buffer
+            .iter_mut()
+            .zip(downsampled[start..start + KEY_FFT_SIZE].iter().zip(&window))
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let mut frame = [0.0f32; 12];
+        for (bin, class) in bin_classes.iter().enumerate() {
+            if let Some(class) = class {
+                frame[*class] += buffer[bin].norm();
+            }
+        }
+        // Normalize each frame so a loud drop doesn't outvote the rest of the track.
+        let total: f32 = frame.iter().sum();
+        if total > f32::EPSILON {
+            for (acc, value) in chroma.iter_mut().zip(frame) {
+                *acc += value / total;
+            }
+        }
+        start += KEY_HOP_SIZE;
+    }
+    chroma
+}
+
+fn pearson(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= f32::EPSILON || var_b <= f32::EPSILON {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+// Estimate the key of a mono signal by correlating its chromagram with the
+// major and minor key profiles in all twelve transpositions.
+pub fn estimate_key(samples: &[f32], sample_rate: u32) -> Option<KeyEstimate> {
+    if (samples.len() as f32) < MIN_ANALYSIS_SECONDS * sample_rate as f32 {
+        return None;
+    }
+    let chroma = chromagram(samples, sample_rate);
+
+    let mut best: Option<KeyEstimate> = None;
+    for tonic in 0..12u8 {
+        for (mode, profile) in [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)] {
+            let mut rotated = [0.0f32; 12];
+            for (i, value) in profile.iter().enumerate() {
+                rotated[(i + tonic as usize) % 12] = *value;
+            }
+            let score = pearson(&chroma, &rotated);
+            if best.is_none_or(|b| score > b.confidence) {
+                best = Some(KeyEstimate { key: MusicalKey { tonic, mode }, confidence: score });
+            }
+        }
+    }
+    best.filter(|b| b.confidence > 0.0)
+}
+
+// Compute a compact acoustic fingerprint: one 32 bit word per frame.
+pub fn fingerprint(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+    let frame_len = (FINGERPRINT_FRAME_SECONDS * sample_rate as f32) as usize;
+    let hop_len = frame_len / 4;
+    if frame_len == 0 || samples.len() < frame_len {
+        return Vec::new();
+    }
+    let limit = samples.len().min((FINGERPRINT_MAX_SECONDS * sample_rate as f32) as usize);
+
+    let bin_width = sample_rate as f32 / frame_len as f32;
+    let band_ranges: Vec<(usize, usize)> = (0..FINGERPRINT_BANDS)
+        .map(|band| {
+            let ratio = FINGERPRINT_MAX_FREQ / FINGERPRINT_MIN_FREQ;
+            let low = FINGERPRINT_MIN_FREQ * ratio.powf(band as f32 / FINGERPRINT_BANDS as f32);
+            let high = FINGERPRINT_MIN_FREQ * ratio.powf((band + 1) as f32 / FINGERPRINT_BANDS as f32);
+            let start = (low / bin_width).floor() as usize;
+            let end = ((high / bin_width).ceil() as usize).max(start + 1);
+            (start, end)
+        })
+        .collect();
+
+    let window: Vec<f32> = (0..frame_len)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame_len as f32).cos())
+        .collect();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(frame_len);
+    let mut buffer = vec![Complex::new(0.0, 0.0); frame_len];
+
+    let mut words = Vec::new();
+    let mut previous: Option<Vec<f32>> = None;
+    let mut start = 0;
+    while start + frame_len <= limit {
+        for (slot, (sample, w)) in buffer.iter_mut().zip(samples[start..start + frame_len].iter().zip(&window)) {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        let energies: Vec<f32> = band_ranges
+            .iter()
+            .map(|&(lo, hi)| buffer[lo..hi].iter().map(|c| c.norm_sqr()).sum())
+            .collect();
+
+        if let Some(prev) = &previous {
+            let mut word = 0u32;
+            for band in 0..FINGERPRINT_BANDS - 1 {
+                let now = energies[band] - energies[band + 1];
+                let before = prev[band] - prev[band + 1];
+                if now - before > 0.0 {
+                    word |= 1 << band;
+                }
+            }
+            words.push(word);
+        }
+        previous = Some(energies);
+        start += hop_len;
+    }
+    words
+}
+
+// 0.0 - 1.0, the fraction of matching fingerprint bits at the best alignment.
+// Unrelated audio hovers around 0.5; re-encodes of the same master usually
+// score above 0.8.
+pub fn fingerprint_similarity(a: &[u32], b: &[u32]) -> f32 {
+    let mut best = 0.0f32;
+    for offset in -FINGERPRINT_MAX_OFFSET..=FINGERPRINT_MAX_OFFSET {
+        let (a_start, b_start) = if offset >= 0 { (offset as usize, 0) } else { (0, (-offset) as usize) };
+        if a_start >= a.len() || b_start >= b.len() {
+            continue;
+        }
+        let overlap = (a.len() - a_start).min(b.len() - b_start);
+        if overlap < FINGERPRINT_MIN_OVERLAP {
+            continue;
+        }
+        let differing: u32 = a[a_start..a_start + overlap]
+            .iter()
+            .zip(&b[b_start..b_start + overlap])
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum();
+        let bits = overlap as f32 * (FINGERPRINT_BANDS - 1) as f32;
+        best = best.max(1.0 - differing as f32 / bits);
+    }
+    best
+}
+
+pub fn encode_fingerprint(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+pub fn decode_fingerprint(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+pub fn fingerprint_file(path: &Path) -> Result<Vec<u32>, Box<dyn Error>> {
+    let audio = decode_file(path)?;
+    Ok(fingerprint(&audio.to_mono(), audio.sample_rate))
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub struct Loudness {
+    // Integrated loudness, EBU R128.
+    pub integrated_lufs: f64,
+    pub true_peak_db: f64,
+    // Gain to bring the track to the ReplayGain 2.0 reference loudness.
+    pub replay_gain_db: f64,
+}
+
+// Second-order IIR section, direct form I.
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    // Butterworth-style low-pass and high-pass sections from the RBJ audio
+    // EQ cookbook.
+    pub fn low_pass(sample_rate: u32, cutoff: f64, q: f64) -> Self {
+        let (cos, alpha) = cookbook_terms(sample_rate, cutoff, q);
+        let a0 = 1.0 + alpha;
+        Biquad::new(
+            [(1.0 - cos) / 2.0 / a0, (1.0 - cos) / a0, (1.0 - cos) / 2.0 / a0],
+            [1.0, -2.0 * cos / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    pub fn high_pass(sample_rate: u32, cutoff: f64, q: f64) -> Self {
+        let (cos, alpha) = cookbook_terms(sample_rate, cutoff, q);
+        let a0 = 1.0 + alpha;
+        Biquad::new(
+            [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0],
+            [1.0, -2.0 * cos / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    // Take on another section's response, keeping this one's history so a
+    // signal running through it doesn't click.
+    pub fn retune(&mut self, design: &Biquad) {
+        self.b = design.b;
+        self.a = design.a;
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+fn cookbook_terms(sample_rate: u32, cutoff: f64, q: f64) -> (f64, f64) {
+    // Keep the cutoff below Nyquist for low sample rates.
+    let w0 = 2.0 * std::f64::consts::PI * cutoff.min(sample_rate as f64 * 0.45) / sample_rate as f64;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
+
+// The BS.1770 K-weighting pre-filter (a high shelf modelling the head) and
+// RLB high-pass, designed for any sample rate rather than only 48kHz.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    [shelf, high_pass]
+}
+
+// Surround channels count for more in BS.1770. Files are nearly always
+// stereo, but in a 5.1 file the LFE (channel 4) is left out and the rear
+// pair weighted up.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn integrated_loudness(audio: &DecodedAudio) -> Option<f64> {
+    let channels = audio.channels;
+    let segment = (audio.sample_rate as f64 * LOUDNESS_SEGMENT_SECONDS) as usize;
+    if segment == 0 {
+        return None;
+    }
+    let mut filters: Vec<[Biquad; 2]> = (0..channels).map(|_| k_weighting(audio.sample_rate)).collect();
+    let weights: Vec<f64> = (0..channels).map(|c| channel_weight(c, channels)).collect();
+
+    // Weighted power summed over each 100ms segment.
+    let mut segments = Vec::new();
+    for chunk in audio.samples.chunks(segment * channels) {
+        if chunk.len() < segment * channels {
+            break;
+        }
+        let mut power = 0.0;
+        for frame in chunk.chunks_exact(channels) {
+            for (c, &sample) in frame.iter().enumerate() {
+                let [shelf, high_pass] = &mut filters[c];
+                let filtered = high_pass.process(shelf.process(sample as f64));
+                power += weights[c] * filtered * filtered;
+            }
+        }
+        segments.push(power);
+    }
+
+    let block_len = (LOUDNESS_BLOCK_SEGMENTS * segment) as f64;
+    let blocks: Vec<f64> = segments
+        .windows(LOUDNESS_BLOCK_SEGMENTS)
+        .map(|window| window.iter().sum::<f64>() / block_len)
+        .collect();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean_power = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let above_absolute: Vec<f64> = blocks.into_iter().filter(|&p| loudness(p) > LOUDNESS_ABSOLUTE_GATE).collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+    let relative_gate = loudness(mean_power(&above_absolute)) + LOUDNESS_RELATIVE_GATE;
+    let gated: Vec<f64> = above_absolute.into_iter().filter(|&p| loudness(p) > relative_gate).collect();
+    if gated.is_empty() {
+        return None;
+    }
+    Some(loudness(mean_power(&gated)))
+}
+
+// Highest sample peak of the signal reconstructed between samples, found by
+// oversampling with a windowed-sinc interpolator.
+fn true_peak(audio: &DecodedAudio) -> f64 {
+    let channels = audio.channels;
+    let mut peak = audio.samples.iter().fold(0f32, |peak, s| peak.max(s.abs())) as f64;
+    // At 4x the usual rates there's nothing left between samples to find.
+    if audio.sample_rate >= 176_400 {
+        return peak;
+    }
+
+    let factor = TRUE_PEAK_OVERSAMPLING;
+    let taps = factor * TRUE_PEAK_TAPS_PER_PHASE;
+    let center = (taps - 1) as f64 / 2.0;
+    let filter: Vec<f64> = (0..taps)
+        .map(|n| {
+            let t = (n as f64 - center) / factor as f64;
+            let sinc = if t == 0.0 { 1.0 } else { (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t) };
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * (n as f64 + 0.5) / taps as f64).cos();
+            sinc * window
+        })
+        .collect();
+    // Each phase of the polyphase filter, scaled to unity gain.
+    let phases: Vec<Vec<f64>> = (0..factor)
+        .map(|phase| {
+            let coefficients: Vec<f64> = filter.iter().skip(phase).step_by(factor).copied().collect();
+            let sum: f64 = coefficients.iter().sum();
+            coefficients.into_iter().map(|c| c / sum).collect()
+        })
+        .collect();
+
+    let frames = audio.samples.len() / channels;
+    for c in 0..channels {
+        let channel: Vec<f64> = (0..frames).map(|i| audio.samples[i * channels + c] as f64).collect();
+        for i in TRUE_PEAK_TAPS_PER_PHASE..frames {
+            for coefficients in &phases {
+                let value: f64 = coefficients.iter().enumerate().map(|(k, h)| h * channel[i - k]).sum();
+                peak = peak.max(value.abs());
+            }
+        }
+    }
+    peak
+}
+
+// Loudness of decoded audio, or None when it's silent (or shorter than one
+// 400ms measurement block).
+pub fn measure_loudness(audio: &DecodedAudio) -> Option<Loudness> {
+    let integrated_lufs = integrated_loudness(audio)?;
+    let peak = true_peak(audio);
+    Some(Loudness {
+        integrated_lufs,
+        true_peak_db: if peak > 0.0 { 20.0 * peak.log10() } else { f64::NEG_INFINITY },
+        replay_gain_db: REPLAYGAIN_REFERENCE_LUFS - integrated_lufs,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct BeatgridEstimate {
+    pub bpm: f64,
+    pub first_downbeat: f64,
+    // Every beat, in seconds, when the tempo drifts too much for a constant
+    // grid. None when the tempo and first downbeat describe the grid.
+    pub beats: Option<Vec<f64>>,
+}
+
+// Seconds into the audio of an onset envelope frame, i.e. where a transient
+// that shows up in that frame's energy rise starts.
+fn envelope_frame_seconds(frame: usize, sample_rate: u32) -> f64 {
+    (frame * HOP_SIZE + FRAME_SIZE) as f64 / sample_rate as f64
+}
+
+// Dynamic-programming beat tracking (Ellis, 2007): every frame's best score
+// is its onset strength plus the best score of an earlier beat, penalized by
+// how far the gap strays from `period` frames on a log scale. Following the
+// links back from the best-scoring frame near the end gives the beats, less
+// the weak ones it carries on with through silence at either end.
+fn track_beats(envelope: &[f32], period: f32) -> Vec<usize> {
+    let mean = envelope.iter().sum::<f32>() / envelope.len().max(1) as f32;
+    let deviation = (envelope.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / envelope.len().max(1) as f32).sqrt();
+    if deviation <= f32::EPSILON {
+        return Vec::new();
+    }
+    let onsets: Vec<f32> = envelope.iter().map(|v| v / deviation).collect();
+
+    let min_gap = (period * BEAT_MIN_SPACING).round().max(1.0) as usize;
+    let max_gap = (period * BEAT_MAX_SPACING).round() as usize;
+    let mut score = vec![0f32; onsets.len()];
+    let mut previous = vec![None; onsets.len()];
+    for t in 0..onsets.len() {
+        let mut best = None;
+        for gap in min_gap..=max_gap.min(t) {
+            let penalty = BEAT_TRACKING_TIGHTNESS * (gap as f32 / period).ln().powi(2);
+            let candidate = score[t - gap] - penalty;
+            if best.is_none_or(|(_, value)| candidate > value) {
+                best = Some((t - gap, candidate));
+            }
+        }
+        score[t] = onsets[t];
+        if let Some((prev, value)) = best.filter(|(_, value)| *value > 0.0) {
+            score[t] += value;
+            previous[t] = Some(prev);
+        }
+    }
+
+    let tail = onsets.len().saturating_sub(period.ceil() as usize);
+    let Some(mut t) = (tail..onsets.len()).max_by(|a, b| score[*a].total_cmp(&score[*b])) else {
+        return Vec::new();
+    };
+    let mut beats = vec![t];
+    while let Some(prev) = previous[t] {
+        beats.push(prev);
+        t = prev;
+    }
+    beats.reverse();
+
+    let power = beats.iter().map(|&beat| onsets[beat].powi(2)).sum::<f32>() / beats.len() as f32;
+    let threshold = 0.5 * power.sqrt();
+    let first = beats.iter().position(|&beat| onsets[beat] >= threshold).unwrap_or(beats.len());
+    let last = beats.iter().rposition(|&beat| onsets[beat] >= threshold).map_or(first, |last| last + 1);
+    beats[first..last.max(first)].to_vec()
+}
+
+// Least-squares line through the beat times against their index, as
+// (seconds per beat, time of beat 0, RMS distance from the line).
+fn fit_grid(times: &[f64]) -> (f64, f64, f64) {
+    let n = times.len() as f64;
+    let mean_index = (n - 1.0) / 2.0;
+    let mean_time = times.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (i, time) in times.iter().enumerate() {
+        covariance += (i as f64 - mean_index) * (time - mean_time);
+        variance += (i as f64 - mean_index).powi(2);
+    }
+    let slope = covariance / variance;
+    let intercept = mean_time - slope * mean_index;
+    let residual = times
+        .iter()
+        .enumerate()
+        .map(|(i, time)| (time - (intercept + slope * i as f64)).powi(2))
+        .sum::<f64>();
+    (slope, intercept, (residual / n).sqrt())
+}
+
+// Find the beats of a mono signal and which of them fall on the first beat of
+// a bar. The beats are tracked at `bpm_hint` (e.g. a BPM the user corrected
+// from half or double tempo) when there is one, and at a detected tempo
+// otherwise. The grid's own BPM comes from the tracked beats either way.
+pub fn estimate_beatgrid(samples: &[f32], sample_rate: u32, bpm_hint: Option<f32>) -> Option<BeatgridEstimate> {
+    let bpm = bpm_hint
+        .or_else(|| estimate_tempo(samples, sample_rate).map(|tempo| tempo.bpm))
+        .filter(|bpm| *bpm > 0.0)?;
+    let envelope = onset_envelope(samples);
+    let frame_rate = sample_rate as f32 / HOP_SIZE as f32;
+    let frames = track_beats(&envelope, 60.0 * frame_rate / bpm);
+    if frames.len() < 2 * BEATS_PER_BAR {
+        return None;
+    }
+
+    // The bar starts on whichever beat in four has the strongest low end.
+    let mut low_pass = [
+        Biquad::low_pass(sample_rate, DOWNBEAT_LOW_PASS, std::f64::consts::FRAC_1_SQRT_2),
+        Biquad::low_pass(sample_rate, DOWNBEAT_LOW_PASS, std::f64::consts::FRAC_1_SQRT_2),
+    ];
+    let low: Vec<f32> = samples
+        .iter()
+        .map(|&sample| low_pass.iter_mut().fold(sample as f64, |value, filter| filter.process(value)) as f32)
+        .collect();
+    let low_envelope = onset_envelope(&low);
+    let phase = (0..BEATS_PER_BAR).max_by(|&a, &b| {
+        let strength = |phase: usize| -> f32 {
+            frames.iter().skip(phase).step_by(BEATS_PER_BAR).map(|&frame| low_envelope.get(frame).copied().unwrap_or(0.0)).sum()
+        };
+        strength(a).total_cmp(&strength(b))
+    })?;
+
+    let times: Vec<f64> = frames.iter().map(|&frame| envelope_frame_seconds(frame, sample_rate)).collect();
+    let (seconds_per_beat, start, residual) = fit_grid(&times);
+    if residual <= CONSTANT_GRID_TOLERANCE_SECONDS {
+        Some(BeatgridEstimate {
+            bpm: 60.0 / seconds_per_beat,
+            first_downbeat: start + seconds_per_beat * phase as f64,
+            beats: None,
+        })
+    } else {
+        Some(BeatgridEstimate { bpm: 60.0 / seconds_per_beat, first_downbeat: times[phase], beats: Some(times) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_keys() -> impl Iterator<Item = MusicalKey> {
+        (0..12).flat_map(|tonic| [Mode::Major, Mode::Minor].map(|mode| MusicalKey { tonic, mode }))
+    }
+
+    #[test]
+    fn camelot_codes_round_trip_for_every_key() {
+        let mut codes: Vec<String> = all_keys().map(|key| key.camelot()).collect();
+        for key in all_keys() {
+            assert_eq!(MusicalKey::parse_camelot(&key.camelot()), Some(key), "{}", key.camelot());
+            assert_eq!(MusicalKey::parse(&key.camelot()), Some(key), "{}", key.camelot());
+            assert_eq!(MusicalKey::parse(&key.standard()), Some(key), "{}", key.standard());
+            assert_eq!(MusicalKey::parse(&key.short()), Some(key), "{}", key.short());
+        }
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), 24);
+    }
+
+    #[test]
+    fn camelot_wheel_anchors() {
+        let c_major = MusicalKey { tonic: 0, mode: Mode::Major };
+        let a_minor = MusicalKey { tonic: 9, mode: Mode::Minor };
+        assert_eq!(c_major.camelot(), "8B");
+        assert_eq!(a_minor.camelot(), "8A");
+        assert_eq!(MusicalKey::parse("1B").map(|key| key.standard()), Some("B major".to_string()));
+        assert_eq!(MusicalKey::parse("12a").map(|key| key.standard()), Some("C# minor".to_string()));
+        // A fifth up is one step clockwise.
+        assert_eq!(MusicalKey { tonic: 7, mode: Mode::Major }.camelot(), "9B");
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_key() {
+        for text in ["", "0A", "13B", "8C", "H minor", "C dorian"] {
+            assert_eq!(MusicalKey::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn fit_grid_recovers_a_straight_line() {
+        let times: Vec<f64> = (0..32).map(|i| 0.3 + i as f64 * 0.46875).collect();
+        let (seconds_per_beat, start, residual) = fit_grid(&times);
+        assert!((seconds_per_beat - 0.46875).abs() < 1e-9);
+        assert!((start - 0.3).abs() < 1e-9);
+        assert!(residual < 1e-9);
+
+        let jittered: Vec<f64> = times.iter().enumerate().map(|(i, t)| t + if i % 2 == 0 { 0.002 } else { -0.002 }).collect();
+        let (_, _, residual) = fit_grid(&jittered);
+        assert!((residual - 0.002).abs() < 1e-4, "{}", residual);
+    }
+
+    #[test]
+    fn track_beats_follows_pulses_and_drops_the_silence_after() {
+        // A pulse every 20 frames for 400 frames, then silence.
+        let mut envelope = vec![0.0f32; 600];
+        for frame in (10..400).step_by(20) {
+            envelope[frame] = 1.0;
+        }
+        let beats = track_beats(&envelope, 20.0);
+        let expected: Vec<usize> = (10..400).step_by(20).collect();
+        assert_eq!(beats, expected);
+    }
+
+    #[test]
+    fn track_beats_finds_nothing_in_silence() {
+        assert!(track_beats(&[0.0; 500], 20.0).is_empty());
+    }
+
+    // A stereo sine at `amplitude` in both channels, `seconds` long.
+    fn stereo_sine(frequency: f32, amplitude: f32, seconds: f32, sample_rate: u32) -> DecodedAudio {
+        let frames = (seconds * sample_rate as f32) as usize;
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (std::f32::consts::TAU * frequency * i as f32 / sample_rate as f32).sin();
+                [sample, sample]
+            })
+            .collect();
+        DecodedAudio { samples, channels: 2, sample_rate }
+    }
+
+    #[test]
+    fn sine_at_minus_20_dbfs_measures_minus_20_lufs() {
+        for sample_rate in [44100, 48000] {
+            let loudness = measure_loudness(&stereo_sine(1000.0, 0.1, 10.0, sample_rate)).unwrap();
+            assert!((loudness.integrated_lufs + 20.0).abs() < 0.1, "{} LUFS", loudness.integrated_lufs);
+            assert!((loudness.true_peak_db + 20.0).abs() < 0.1, "{} dBTP", loudness.true_peak_db);
+            assert!((loudness.replay_gain_db - (REPLAYGAIN_REFERENCE_LUFS + 20.0)).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn loudness_follows_level() {
+        let quiet = integrated_loudness(&stereo_sine(1000.0, 0.05, 10.0, 48000)).unwrap();
+        let loud = integrated_loudness(&stereo_sine(1000.0, 0.1, 10.0, 48000)).unwrap();
+        assert!((loud - quiet - 6.02).abs() < 0.1, "{} vs {}", loud, quiet);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let silence = DecodedAudio { samples: vec![0.0; 2 * 48000 * 5], channels: 2, sample_rate: 48000 };
+        assert!(measure_loudness(&silence).is_none());
+    }
+}
@@ -1,11 +1,25 @@
-use once_cell::sync::Lazy;
-use rodio::{Decoder, OutputStream, Sink};
+use once_cell::sync::{Lazy, OnceCell};
+use rodio::source::SamplesConverter;
+use rodio::cpal::traits::HostTrait;
+use rodio::{Decoder, DeviceTrait, OutputStream, OutputStreamHandle, Sink, Source};
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
 use std::ops::Not;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+use crate::analysis;
+use crate::db;
+use crate::jobs;
+use crate::stretch::{self, Speed, SpeedControl, SpeedMode, Stretch};
+use crate::track::{Track, TrackControl};
+use crate::normalization::Normalization;
+use crate::transition::Transition;
 
 static AUDIO_SENDER: Lazy<Sender<AudioMessage>> = Lazy::new(|| {
     let (sender, receiver) = channel();
@@ -13,128 +27,1417 @@ static AUDIO_SENDER: Lazy<Sender<AudioMessage>> = Lazy::new(|| {
     sender
 });
 
+// The preview player, for listening ahead on another device (typically
+// headphones) without touching the main output.
+static PREVIEW_SENDER: Lazy<Sender<PreviewMessage>> = Lazy::new(|| {
+    let (sender, receiver) = channel();
+    thread::spawn(move || preview_thread(receiver));
+    sender
+});
+
+// Set once at startup so the audio thread can push events to the frontend.
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+
+const PLAYBACK_EVENT: &str = "playback";
+const PREVIEW_EVENT: &str = "preview";
+
+pub const DEFAULT_TICK_RATE: f32 = 4.0;
+const MIN_TICK_RATE: f32 = 1.0;
+const MAX_TICK_RATE: f32 = 60.0;
+
+// A seek normally completes within one 5ms rodio callback; this only guards
+// against a wedged audio thread.
+const SEEK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Past this point "previous" restarts the current track instead.
+const PREVIOUS_RESTART_SECONDS: f32 = 3.0;
+
+// How far ahead of the end a gapless transition appends the next track. Queue
+// changes after this point take the appended track back out again.
+const GAPLESS_PRELOAD_SECONDS: f32 = 5.0;
+
+// How often the audio thread checks that its output device is still there,
+// and retries opening one when there's none.
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+const BEATS_PER_BAR: f32 = analysis::BEATS_PER_BAR as f32;
+
 #[derive(Debug, Clone)]
 enum AudioMessage {
-    Play(String),
+    // Replace the queue and start playing `entries[start]`.
+    Play { entries: Vec<QueueEntry>, start: usize },
+    Enqueue(Vec<QueueEntry>),
+    PlayNext(Vec<QueueEntry>),
+    Skip,
+    Previous,
+    ClearQueue,
+    MoveInQueue { from: usize, to: usize },
+    GetQueue(Sender<PlayQueue>),
     Pause,
     Resume,
     Stop,
     SetVolume(f32),
     GetState(Sender<AudioState>),
-    Seek(f32),
+    Seek { seconds: f32, reply: Sender<Result<f32, SeekError>> },
+    // Seek to a saved cue point of the loaded beat.
+    JumpToCue { cue_id: i64, reply: Sender<Result<f32, String>> },
+    // Position ticks per second while playing.
+    SetTickRate(f32),
+    SetTransition(Transition),
+    SetNormalization(Normalization),
+    // Set loop points on the loaded track and start looping.
+    SetLoop { start: f32, end: f32, unit: LoopUnit, reply: Sender<Result<LoopPoints, String>> },
+    ToggleLoop(Sender<Result<bool, String>>),
+    ClearLoop,
+    // Playback speed relative to the original tempo.
+    SetPlaybackRate(f32),
+    SetSpeedMode(SpeedMode),
+    SetPitchShift(f32),
+    // Move playback to the named output device, or the system default.
+    SetOutputDevice { name: Option<String>, reply: Sender<Result<String, String>> },
+}
+
+impl AudioMessage {
+    // Messages that only change how things play, which are worth keeping
+    // while there's no output to play on.
+    fn is_setting(&self) -> bool {
+        matches!(
+            self,
+            AudioMessage::SetVolume(_)
+                | AudioMessage::SetTickRate(_)
+                | AudioMessage::SetTransition(_)
+                | AudioMessage::SetNormalization(_)
+                | AudioMessage::SetPlaybackRate(_)
+                | AudioMessage::SetSpeedMode(_)
+                | AudioMessage::SetPitchShift(_)
+        )
+    }
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PlaybackEvent {
+    Started { file_path: String, beat_id: Option<u32>, duration: f32 },
+    Position { pos: f32, duration: f32, beat_id: Option<u32> },
+    Paused { pos: f32 },
+    Resumed { pos: f32 },
+    Seeked { pos: f32 },
+    Stopped,
+    // The loaded track played through to the end.
+    Ended { file_path: String, beat_id: Option<u32> },
+    QueueChanged { queue: PlayQueue },
+    LoopChanged { loop_points: Option<LoopPoints> },
+    SpeedChanged { speed: Speed },
+    DeviceChanged { device: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone)]
+pub enum SeekError {
+    NothingLoaded,
+    InvalidPosition(f32),
+    // The decoder can't seek in this file at all.
+    Unsupported(String),
+    Failed(String),
+    // The audio thread didn't answer.
+    Unavailable(String),
+}
+
+impl fmt::Display for SeekError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeekError::NothingLoaded => write!(f, "Nothing is loaded to seek in"),
+            SeekError::InvalidPosition(seconds) => write!(f, "Can't seek to {} seconds", seconds),
+            SeekError::Unsupported(e) => write!(f, "Seeking is not supported for this file: {}", e),
+            SeekError::Failed(e) => write!(f, "Seek failed: {}", e),
+            SeekError::Unavailable(e) => write!(f, "Audio thread unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SeekError {}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct QueueEntry {
+    pub beat_id: Option<u32>,
+    pub file_path: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct PlayQueue {
+    entries: Vec<QueueEntry>,
+    // The entry that's loaded, or was last played once the queue runs out.
+    current: Option<usize>,
+}
+
+impl PlayQueue {
+    fn next_index(&self) -> usize {
+        self.current.map_or(0, |current| current + 1)
+    }
+
+    fn move_entry(&mut self, from: usize, to: usize) -> Result<(), String> {
+        if from >= self.entries.len() || to >= self.entries.len() {
+            return Err(format!("Queue position out of range (queue has {} entries)", self.entries.len()));
+        }
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        // Keep pointing at the same entry.
+        self.current = self.current.map(|current| {
+            if current == from {
+                to
+            } else if from < current && current <= to {
+                current - 1
+            } else if to <= current && current < from {
+                current + 1
+            } else {
+                current
+            }
+        });
+        Ok(())
+    }
+
+    // Drop everything except the loaded entry.
+    fn clear(&mut self, keep_current: bool) {
+        let current = self.current.filter(|_| keep_current).map(|index| self.entries[index].clone());
+        self.current = current.as_ref().map(|_| 0);
+        self.entries = current.into_iter().collect();
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopUnit {
+    Seconds,
+    // Bars from the start of the track, at the beat's BPM.
+    Bars,
+}
+
+impl LoopUnit {
+    fn to_seconds(self, value: f32, bpm: Option<f32>) -> Result<f32, String> {
+        match self {
+            LoopUnit::Seconds => Ok(value),
+            LoopUnit::Bars => bpm
+                .filter(|bpm| *bpm > 0.0)
+                .map(|bpm| value * BEATS_PER_BAR * 60.0 / bpm)
+                .ok_or_else(|| "Loop points in bars need the beat's BPM".to_string()),
+        }
+    }
+}
+
+// Loop in and out points of the loaded track, in seconds.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct LoopPoints {
+    start: f32,
+    end: f32,
+    enabled: bool,
+}
+
+// Convert loop points to seconds and check they make a loop within a track
+// of `duration` seconds (0 when unknown).
+fn loop_seconds(start: f32, end: f32, unit: LoopUnit, bpm: Option<f32>, duration: f32) -> Result<(f32, f32), String> {
+    let start = unit.to_seconds(start, bpm)?;
+    let end = unit.to_seconds(end, bpm)?;
+    if !start.is_finite() || !end.is_finite() || start < 0.0 || end <= start {
+        return Err(format!("Can't loop from {} to {} seconds", start, end));
+    }
+    if duration > 0.0 && end > duration {
+        return Err(format!("Loop end {:.2}s is past the end of the track ({:.2}s)", end, duration));
+    }
+    Ok((start, end))
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
 pub struct AudioState {
     is_playing: bool,
     pos: f32,
     duration: f32,
+    file_path: Option<String>,
+    beat_id: Option<u32>,
+    loop_points: Option<LoopPoints>,
+    speed: Speed,
+    output_device: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct OutputDevice {
+    name: String,
+    is_default: bool,
+    is_active: bool,
+}
+
+// An output stream with the two decks playing on it.
+struct Output {
+    stream: OutputStream,
+    sink: Sink,
+    standby: Sink,
+    // None when the device wouldn't give its name.
+    device: Option<String>,
+}
+
+// Open `name`, or the system default output device.
+fn open_output(name: Option<&str>) -> Result<Output, String> {
+    let (stream, stream_handle, device) = open_stream(name)?;
+    let sink = Sink::try_new(&stream_handle)
+        .map_err(|e| format!("Failed to create audio sink: {}", e))?;
+    let standby = Sink::try_new(&stream_handle)
+        .map_err(|e| format!("Failed to create audio sink: {}", e))?;
+    Ok(Output { stream, sink, standby, device })
+}
+
+fn open_stream(name: Option<&str>) -> Result<(OutputStream, OutputStreamHandle, Option<String>), String> {
+    let host = rodio::cpal::default_host();
+    let device = match name {
+        Some(name) => host
+            .output_devices()
+            .map_err(|e| format!("Failed to list output devices: {}", e))?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| format!("Output device not found: {}", name))?,
+        None => host
+            .default_output_device()
+            .ok_or_else(|| "No audio output device available".to_string())?,
+    };
+    let (stream, stream_handle) = OutputStream::try_from_device(&device)
+        .map_err(|e| format!("Failed to create audio output stream: {}", e))?;
+    Ok((stream, stream_handle, device.name().ok()))
+}
+
+fn device_label(name: Option<&str>) -> String {
+    name.unwrap_or("Unknown device").to_string()
+}
+
+fn output_device_names() -> Result<Vec<String>, String> {
+    let devices = rodio::cpal::default_host()
+        .output_devices()
+        .map_err(|e| format!("Failed to list output devices: {}", e))?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+// Keeps an eye on the output devices for a player. Devices are only looked
+// at again once the list of them changes, so a device that won't give its
+// name isn't reopened on every check, and a switch that fails (say, with
+// nothing plugged in at all) is reported once rather than every time.
+struct DeviceWatch {
+    // The device in use, when its name could be read.
+    active: Option<String>,
+    // The output devices as of the last check.
+    known: Vec<String>,
+    last_check: Instant,
+}
+
+impl DeviceWatch {
+    fn new() -> Self {
+        DeviceWatch { active: None, known: output_device_names().unwrap_or_default(), last_check: Instant::now() }
+    }
+
+    // Where to move when the devices have changed: back to the preferred one
+    // once it's there, or to the default (None) when the active one is gone.
+    // A device without a name can't be looked for, so it counts as gone.
+    fn check(&mut self, preferred: Option<&str>) -> Option<Option<String>> {
+        if self.last_check.elapsed() < DEVICE_CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let names = match output_device_names() {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("{}", e);
+                return None;
+            }
+        };
+        if names == self.known {
+            return None;
+        }
+        self.known = names;
+        let present = |name: &str| self.known.iter().any(|known| known == name);
+        match preferred {
+            Some(preferred) if self.active.as_deref() != Some(preferred) && present(preferred) => Some(Some(preferred.to_string())),
+            _ if !self.active.as_deref().is_some_and(present) => Some(None),
+            _ => None,
+        }
+    }
 }
 
 struct AudioManager {
     _stream: OutputStream,
+    // The deck playing the current track.
     sink: Sink,
+    // The other deck, where the previous track fades out during a crossfade.
+    standby: Sink,
     state: AudioState,
+    tick_interval: Duration,
+    last_tick: Instant,
+    queue: PlayQueue,
+    transition: Transition,
+    normalization: Normalization,
+    current: Option<Arc<TrackControl>>,
+    // Followed by every track, on both decks.
+    speed_control: Arc<SpeedControl>,
+    // Crossfade length for the current track, once the transition mode and
+    // its BPM are known.
+    current_fade: Option<f32>,
+    preloaded: Option<Preloaded>,
+    // Set when the next track couldn't be lined up early, so the transition
+    // falls back to a cut instead of being retried on every poll.
+    transition_failed: bool,
+    // The device picked in settings, which may not be the one in use if it
+    // went away.
+    preferred_device: Option<String>,
+    device_watch: DeviceWatch,
+    volume: f32,
+}
+
+// The next queue entry, already appended behind the current track for a
+// gapless hand-over.
+struct Preloaded {
+    index: usize,
+    entry: QueueEntry,
+    duration: f32,
+    control: Arc<TrackControl>,
 }
 
 impl AudioManager {
-    fn new() -> Result<Self, String> {
-        let (_stream, stream_handle) = OutputStream::try_default()
-            .map_err(|e| format!("Failed to create audio output stream: {}", e))?;
-        let sink = Sink::try_new(&stream_handle)
-            .map_err(|e| format!("Failed to create audio sink: {}", e))?;
-        Ok(AudioManager { _stream, sink, state: AudioState {
-            is_playing: false,
-            pos: 0.0,
-            duration: 0.0,
-        }})
-    }
-
-    fn play(&self, file_path: &str) -> Result<(), String> {
-        let file = File::open(file_path)
-            .map_err(|e| format!("Failed to open file {}: {}", file_path, e))?;
-        let source = Decoder::new(BufReader::new(file))
-            .map_err(|e| format!("Failed to decode audio: {}", e))?;
-        self.sink.append(source);
+    // Plays on `preferred_device` when it's there, and on the default device
+    // until it is.
+    fn new(preferred_device: Option<String>) -> Result<Self, String> {
+        let output = match preferred_device.as_deref().map(|name| open_output(Some(name))) {
+            Some(Ok(output)) => output,
+            Some(Err(e)) => {
+                eprintln!("{}, using the default device", e);
+                open_output(None)?
+            }
+            None => open_output(None)?,
+        };
+        let device = device_label(output.device.as_deref());
+        println!("Playing audio on {}", device);
+        Ok(AudioManager {
+            _stream: output.stream,
+            sink: output.sink,
+            standby: output.standby,
+            state: AudioState { output_device: device, ..AudioState::default() },
+            tick_interval: Duration::from_secs_f32(1.0 / DEFAULT_TICK_RATE),
+            last_tick: Instant::now(),
+            queue: PlayQueue::default(),
+            transition: Transition::default(),
+            normalization: Normalization::default(),
+            current: None,
+            speed_control: Arc::new(SpeedControl::default()),
+            current_fade: None,
+            preloaded: None,
+            transition_failed: false,
+            preferred_device,
+            device_watch: DeviceWatch { active: output.device, ..DeviceWatch::new() },
+            volume: 1.0,
+        })
+    }
+
+    // Start playing queue entry `index`. With `replace` whatever is playing
+    // is cut off first; otherwise the sink has run dry and the entry simply
+    // follows on.
+    fn load(&mut self, index: usize, replace: bool) -> Result<(), String> {
+        let entry = self.queue.entries[index].clone();
+        let (track, duration, control) = self.open_entry(&entry, Duration::ZERO)?;
+        if replace {
+            self.cancel_preload();
+            self.sink.clear();
+            self.standby.clear();
+        }
+        self.sink.append(track);
         self.sink.play();
+        self.set_current(index, entry, duration, control);
+        Ok(())
+    }
+
+    // Load the first playable entry from `index` on, reporting the ones that
+    // fail. Stops when the end of the queue is reached.
+    fn load_from(&mut self, mut index: usize, replace: bool) {
+        while index < self.queue.entries.len() {
+            match self.load(index, replace) {
+                Ok(()) => return,
+                Err(e) => {
+                    eprintln!("Error playing audio: {}", e);
+                    emit(PlaybackEvent::Error { message: e });
+                    index += 1;
+                }
+            }
+        }
+        self.stop();
+    }
+
+    fn set_current(&mut self, index: usize, entry: QueueEntry, duration: f32, control: Arc<TrackControl>) {
+        self.state.duration = duration;
+        self.state.file_path = Some(entry.file_path.clone());
+        self.state.beat_id = entry.beat_id;
+        if let Some(beat_id) = entry.beat_id {
+            jobs::prioritize(beat_id);
+        }
+        self.queue.current = Some(index);
+        self.current = Some(control);
+        self.current_fade = self.fade_for_current();
+        self.transition_failed = false;
+        emit(PlaybackEvent::Started { file_path: entry.file_path, beat_id: entry.beat_id, duration });
+        if self.state.loop_points.take().is_some() {
+            emit(PlaybackEvent::LoopChanged { loop_points: None });
+        }
+        self.queue_changed();
+    }
+
+    fn open_entry(&self, entry: &QueueEntry, fade_in: Duration) -> Result<(TrackSource, f32, Arc<TrackControl>), String> {
+        let (track, duration, control) = open_track(&entry.file_path, fade_in, &self.speed_control)?;
+        control.set_level(self.level_for(entry));
+        Ok((track, duration, control))
+    }
+
+    // Normalization gain for an entry, from the loudness measured at import.
+    fn level_for(&self, entry: &QueueEntry) -> f32 {
+        if self.normalization == Normalization::Off {
+            return 1.0;
+        }
+        match entry.beat_id.map(db::get_beat) {
+            Some(Ok(beat)) => self.normalization.gain(beat.loudness_lufs(), beat.true_peak_db()),
+            _ => 1.0,
+        }
+    }
+
+    // Change the normalization mode, including for the tracks already
+    // playing or lined up.
+    fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+        let current = self.queue.current.and_then(|index| self.queue.entries.get(index));
+        if let (Some(control), Some(entry)) = (&self.current, current) {
+            control.set_level(self.level_for(entry));
+        }
+        if let Some(preloaded) = &self.preloaded {
+            preloaded.control.set_level(self.level_for(&preloaded.entry));
+        }
+    }
+
+    fn current_bpm(&self) -> Option<f32> {
+        self.state
+            .beat_id
+            .and_then(|beat_id| db::get_beat(beat_id).ok())
+            .map(|beat| beat.bpm() as f32)
+    }
+
+    fn fade_for_current(&self) -> Option<f32> {
+        // A fade longer than half the track would start before it got going.
+        self.transition
+            .fade_seconds(self.current_bpm())
+            .map(|seconds| seconds.min(self.state.duration / 2.0))
+    }
+
+    // Where playback is in the current track, as reported by the track
+    // itself so it stays right across loop wraps.
+    fn position(&self) -> f32 {
+        self.current.as_ref().map_or(0.0, |current| current.position().as_secs_f32())
+    }
+
+    fn looping(&self) -> bool {
+        self.state.loop_points.is_some_and(|points| points.enabled)
+    }
+
+    fn set_loop(&mut self, start: f32, end: f32, unit: LoopUnit) -> Result<LoopPoints, String> {
+        if self.state.file_path.is_none() {
+            return Err("Nothing is loaded to loop".to_string());
+        }
+        let (start, end) = loop_seconds(start, end, unit, self.current_bpm(), self.state.duration)?;
+        let points = LoopPoints { start, end, enabled: true };
+        self.apply_loop(Some(points));
+        Ok(points)
+    }
+
+    // Turn the loop on or off, keeping its points. Returns whether it's on.
+    fn toggle_loop(&mut self) -> Result<bool, String> {
+        let mut points = self.state.loop_points.ok_or_else(|| "No loop points are set".to_string())?;
+        points.enabled = !points.enabled;
+        self.apply_loop(Some(points));
+        Ok(points.enabled)
+    }
+
+    fn apply_loop(&mut self, points: Option<LoopPoints>) {
+        self.state.loop_points = points;
+        if let Some(current) = &self.current {
+            let (start, end, enabled) = points.map_or((0.0, 0.0, false), |p| (p.start, p.end, p.enabled));
+            current.set_loop(Duration::from_secs_f32(start), Duration::from_secs_f32(end), enabled);
+        }
+        // A looping track never reaches its end, so nothing should be lined
+        // up behind it.
+        if self.looping() {
+            self.cancel_preload();
+        }
+        emit(PlaybackEvent::LoopChanged { loop_points: points });
+    }
+
+    fn play_queue(&mut self, entries: Vec<QueueEntry>, start: usize) {
+        self.queue = PlayQueue { entries, current: None };
+        self.load_from(start, true);
+    }
+
+    fn enqueue(&mut self, entries: Vec<QueueEntry>) {
+        self.queue.entries.extend(entries);
+        self.queue_changed();
+    }
+
+    fn play_next(&mut self, entries: Vec<QueueEntry>) {
+        self.cancel_preload();
+        let at = self.queue.next_index();
+        self.queue.entries.splice(at..at, entries);
+        self.queue_changed();
+    }
+
+    fn skip(&mut self) {
+        let next = self.queue.next_index();
+        self.load_from(next, true);
+    }
+
+    fn previous(&mut self) {
+        match self.queue.current {
+            Some(current) if current > 0 && self.position() <= PREVIOUS_RESTART_SECONDS => {
+                if let Err(e) = self.load(current - 1, true) {
+                    eprintln!("Error playing audio: {}", e);
+                    emit(PlaybackEvent::Error { message: e });
+                }
+            }
+            _ => {
+                if let Err(e) = self.seek(0.0) {
+                    eprintln!("Failed to restart track: {}", e);
+                }
+            }
+        }
+    }
+
+    fn clear_queue(&mut self) {
+        self.cancel_preload();
+        let loaded = self.state.file_path.is_some();
+        self.queue.clear(loaded);
+        self.queue_changed();
+    }
+
+    fn move_in_queue(&mut self, from: usize, to: usize) {
+        self.cancel_preload();
+        match self.queue.move_entry(from, to) {
+            Ok(()) => self.queue_changed(),
+            Err(message) => emit(PlaybackEvent::Error { message }),
+        }
+    }
+
+    fn queue_changed(&self) {
+        emit(PlaybackEvent::QueueChanged { queue: self.queue.clone() });
+    }
+
+    fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
+        self.cancel_preload();
+        self.current_fade = self.fade_for_current();
+    }
+
+    // Take back a track lined up for a gapless hand-over, e.g. because the
+    // queue changed and something else is next now.
+    // One that's already playing can't be taken back, so it becomes the
+    // current track instead. Call before changing the queue, so its index
+    // still points at it.
+    fn cancel_preload(&mut self) {
+        self.promote_preload();
+        if let Some(preloaded) = self.preloaded.take() {
+            preloaded.control.cancel();
+        }
+        self.transition_failed = false;
+    }
+
+    // Make the preloaded track current once the sink has moved on to it.
+    fn promote_preload(&mut self) {
+        if let Some(preloaded) = self.preloaded.take_if(|preloaded| preloaded.control.has_started()) {
+            self.finish_current();
+            self.set_current(preloaded.index, preloaded.entry, preloaded.duration, preloaded.control);
+        }
+    }
+
+    // Append the next entry behind the current track on the same sink.
+    fn preload(&mut self, index: usize) -> Result<(), String> {
+        let entry = self.queue.entries[index].clone();
+        let (track, duration, control) = self.open_entry(&entry, Duration::ZERO)?;
+        self.sink.append(track);
+        self.preloaded = Some(Preloaded { index, entry, duration, control });
         Ok(())
     }
 
-    fn append(&self, file_path: &str) -> Result<(), String> {
-        let file = File::open(file_path)
-            .map_err(|e| format!("Failed to open file {}: {}", file_path, e))?;
-        let source = Decoder::new(BufReader::new(file))
-            .map_err(|e| format!("Failed to decode audio: {}", e))?;
-        self.sink.append(source);
+    // Start the next entry on the standby deck fading in while the current
+    // track fades out, then swap decks so the new track is the current one.
+    fn crossfade(&mut self, index: usize, fade: f32) -> Result<(), String> {
+        let entry = self.queue.entries[index].clone();
+        let fade = Duration::from_secs_f32(fade);
+        let (track, duration, control) = self.open_entry(&entry, fade)?;
+        self.standby.clear();
+        self.standby.append(track);
+        self.standby.play();
+        if let Some(current) = &self.current {
+            current.fade_out(fade);
+        }
+        std::mem::swap(&mut self.sink, &mut self.standby);
+        self.finish_current();
+        self.set_current(index, entry, duration, control);
         Ok(())
     }
 
+    // Line up the next track once the current one is close enough to its end
+    // for the transition mode.
+    fn schedule_transition(&mut self) {
+        if self.transition_failed || self.preloaded.is_some() || self.looping() || self.state.duration <= 0.0 {
+            return;
+        }
+        let next = self.queue.next_index();
+        if next >= self.queue.entries.len() {
+            return;
+        }
+        let remaining = self.state.duration - self.position();
+        let result = match (self.transition, self.current_fade) {
+            (Transition::Crossfade { .. }, Some(fade)) if remaining <= fade => self.crossfade(next, fade),
+            // A crossfade in beats without a BPM to go by is played gapless.
+            (Transition::Gapless, _) | (Transition::Crossfade { .. }, None) if remaining <= GAPLESS_PRELOAD_SECONDS => {
+                self.preload(next)
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Error preparing the next track: {}", e);
+            emit(PlaybackEvent::Error { message: e });
+            self.transition_failed = true;
+        }
+    }
+
+    // How long the audio thread can wait for a message before it has to
+    // poll again, so a crossfade starts on time.
+    fn wake_interval(&self) -> Duration {
+        let waiting = !self.sink.is_paused()
+            && !self.sink.empty()
+            && !self.transition_failed
+            && !self.looping()
+            && self.queue.next_index() < self.queue.entries.len();
+        let fade_due = match self.current_fade {
+            // Track time passes faster or slower than real time at other speeds.
+            Some(fade) if waiting => (self.state.duration - fade - self.position()) / self.state.speed.rate,
+            _ => f32::MAX,
+        };
+        self.tick_interval.min(Duration::from_secs_f32(fade_due.clamp(0.001, 3600.0)))
+    }
+
     fn pause(&self) {
         self.sink.pause();
+        self.standby.pause();
+        emit(PlaybackEvent::Paused { pos: self.position() });
     }
 
     fn play_sink(&self) {
         self.sink.play();
+        self.standby.play();
+        emit(PlaybackEvent::Resumed { pos: self.position() });
     }
 
-    fn stop(&self) {
+    fn stop(&mut self) {
+        self.cancel_preload();
         self.sink.clear();
+        self.standby.clear();
+        self.unload();
+        emit(PlaybackEvent::Stopped);
+    }
+
+    fn unload(&mut self) {
+        self.state.duration = 0.0;
+        self.state.file_path = None;
+        self.state.beat_id = None;
+        self.state.loop_points = None;
+        self.current = None;
+        self.current_fade = None;
     }
 
-    fn set_volume(&self, volume: f32) {
+    fn finish_current(&mut self) {
+        if let Some(file_path) = self.state.file_path.clone() {
+            let beat_id = self.state.beat_id;
+            self.unload();
+            emit(PlaybackEvent::Ended { file_path, beat_id });
+        }
+    }
+
+    fn set_speed(&mut self, speed: Speed) {
+        let speed = Speed {
+            rate: speed.rate.clamp(stretch::MIN_PLAYBACK_RATE, stretch::MAX_PLAYBACK_RATE),
+            mode: speed.mode,
+            semitones: speed.semitones.clamp(-stretch::MAX_PITCH_SEMITONES, stretch::MAX_PITCH_SEMITONES),
+        };
+        self.state.speed = speed;
+        self.speed_control.set(speed);
+        emit(PlaybackEvent::SpeedChanged { speed });
+    }
+
+    fn set_output_device(&mut self, name: Option<String>) -> Result<String, String> {
+        let device = self.switch_output(name.as_deref())?;
+        self.preferred_device = name;
+        Ok(device)
+    }
+
+    // Move both decks to another device. Whatever was loaded is reopened
+    // there at the same position, paused if it was paused.
+    fn switch_output(&mut self, name: Option<&str>) -> Result<String, String> {
+        let output = open_output(name)?;
+        self.cancel_preload();
+        let resume = self
+            .queue
+            .current
+            .filter(|_| self.state.file_path.is_some())
+            .map(|index| (index, self.position(), self.sink.is_paused(), self.state.loop_points));
+        self.sink.clear();
+        self.standby.clear();
+        self._stream = output.stream;
+        self.sink = output.sink;
+        self.standby = output.standby;
+        self.sink.set_volume(self.volume);
+        self.standby.set_volume(self.volume);
+        let device = device_label(output.device.as_deref());
+        self.device_watch.active = output.device;
+        self.state.output_device = device.clone();
+        println!("Playing audio on {}", device);
+        emit(PlaybackEvent::DeviceChanged { device: device.clone() });
+
+        if let Some((index, pos, paused, loop_points)) = resume {
+            if let Err(e) = self.load(index, true) {
+                eprintln!("Error reopening audio: {}", e);
+                emit(PlaybackEvent::Error { message: e });
+                self.unload();
+                return Ok(device);
+            }
+            if paused {
+                self.sink.pause();
+            }
+            if let Err(e) = self.seek(pos) {
+                eprintln!("Failed to restore position: {}", e);
+            }
+            if loop_points.is_some() {
+                self.apply_loop(loop_points);
+            }
+        }
+        Ok(device)
+    }
+
+    // Fall back to the default device when the one in use disappears, and
+    // go back to the preferred one once it's plugged in again.
+    fn check_output_device(&mut self) {
+        let Some(target) = self.device_watch.check(self.preferred_device.as_deref()) else {
+            return;
+        };
+        eprintln!("Output device {} changed, switching to {}", self.state.output_device, target.as_deref().unwrap_or("the default device"));
+        if let Err(e) = self.switch_output(target.as_deref()) {
+            eprintln!("Failed to switch output device: {}", e);
+            emit(PlaybackEvent::Error { message: e });
+        }
+    }
+
+    fn set_tick_rate(&mut self, rate: f32) {
+        self.tick_interval = Duration::from_secs_f32(1.0 / rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE));
+    }
+
+    // Called between messages: hand over to the next track in the queue when
+    // the current one ends (or is about to, depending on the transition
+    // mode), and send position ticks while something is playing.
+    fn poll(&mut self) {
+        self.check_output_device();
+        self.promote_preload();
+        if self.sink.empty() {
+            if self.state.file_path.is_some() {
+                self.finish_current();
+                let next = self.queue.next_index();
+                if next < self.queue.entries.len() {
+                    self.load_from(next, false);
+                }
+            }
+            return;
+        }
+        if self.sink.is_paused() {
+            return;
+        }
+        self.schedule_transition();
+        if self.last_tick.elapsed() < self.tick_interval {
+            return;
+        }
+        self.last_tick = Instant::now();
+        emit(PlaybackEvent::Position {
+            pos: self.position(),
+            duration: self.state.duration,
+            beat_id: self.state.beat_id,
+        });
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
         self.sink.set_volume(volume);
+        self.standby.set_volume(volume);
     }
     // get_pos of the current playing beat, duration, and is_playing
     fn get_state(&mut self) -> AudioState {
-        self.state.pos = self.sink.get_pos().as_secs_f32();
-        self.state.is_playing = self.sink.is_paused().not();
+        self.state.pos = self.position();
+        // A sink that ran out of audio isn't paused, but isn't playing either.
+        self.state.is_playing = self.sink.is_paused().not() && !self.sink.empty();
         self.state.clone()
     }
-    fn seek(&self, seconds: f32) {
-        let _ = self.sink.try_seek(Duration::from_secs_f32(seconds));
+    // Seek within the current track and return where playback ended up.
+    fn seek(&self, seconds: f32) -> Result<f32, SeekError> {
+        let target = seek_sink(&self.sink, &self.state, seconds)?;
+        emit(PlaybackEvent::Seeked { pos: target });
+        Ok(target)
+    }
+
+    fn jump_to_cue(&self, cue_id: i64) -> Result<f32, String> {
+        let cue = db::get_cue_point(cue_id).map_err(|e| format!("Cue point {} not found: {}", cue_id, e))?;
+        if self.state.beat_id != Some(cue.beat_id) {
+            return Err(format!("Load beat {} to jump to cue \"{}\"", cue.beat_id, cue.label));
+        }
+        self.seek(cue.position_seconds).map_err(|e| e.to_string())
+    }
+
+    fn handle(&mut self, message: AudioMessage) {
+        match message {
+            AudioMessage::Play { entries, start } => self.play_queue(entries, start),
+            AudioMessage::Enqueue(entries) => self.enqueue(entries),
+            AudioMessage::PlayNext(entries) => self.play_next(entries),
+            AudioMessage::Skip => self.skip(),
+            AudioMessage::Previous => self.previous(),
+            AudioMessage::ClearQueue => self.clear_queue(),
+            AudioMessage::MoveInQueue { from, to } => self.move_in_queue(from, to),
+            AudioMessage::GetQueue(sender) => {
+                if sender.send(self.queue.clone()).is_err() {
+                    eprintln!("Failed to send play queue");
+                }
+            }
+            AudioMessage::Pause => self.pause(),
+            AudioMessage::Resume => self.play_sink(),
+            AudioMessage::Stop => self.stop(),
+            AudioMessage::SetVolume(volume) => self.set_volume(volume),
+            AudioMessage::GetState(sender) => {
+                let state: AudioState = self.get_state();
+                if sender.send(state).is_err() {
+                    eprintln!("Failed to send audio state");
+                }
+            }
+            AudioMessage::Seek { seconds, reply } => {
+                if reply.send(self.seek(seconds)).is_err() {
+                    eprintln!("Failed to send seek result");
+                }
+            }
+            AudioMessage::JumpToCue { cue_id, reply } => {
+                if reply.send(self.jump_to_cue(cue_id)).is_err() {
+                    eprintln!("Failed to send seek result");
+                }
+            }
+            AudioMessage::SetTickRate(rate) => self.set_tick_rate(rate),
+            AudioMessage::SetTransition(transition) => self.set_transition(transition),
+            AudioMessage::SetNormalization(normalization) => self.set_normalization(normalization),
+            AudioMessage::SetLoop { start, end, unit, reply } => {
+                if reply.send(self.set_loop(start, end, unit)).is_err() {
+                    eprintln!("Failed to send loop result");
+                }
+            }
+            AudioMessage::ToggleLoop(reply) => {
+                if reply.send(self.toggle_loop()).is_err() {
+                    eprintln!("Failed to send loop result");
+                }
+            }
+            AudioMessage::ClearLoop => self.apply_loop(None),
+            AudioMessage::SetPlaybackRate(rate) => self.set_speed(Speed { rate, ..self.state.speed }),
+            AudioMessage::SetSpeedMode(mode) => self.set_speed(Speed { mode, ..self.state.speed }),
+            AudioMessage::SetPitchShift(semitones) => self.set_speed(Speed { semitones, ..self.state.speed }),
+            AudioMessage::SetOutputDevice { name, reply } => {
+                if reply.send(self.set_output_device(name)).is_err() {
+                    eprintln!("Failed to send output device result");
+                }
+            }
+        }
     }
 
 }
 
-fn audio_thread(receiver: Receiver<AudioMessage>) {
-    let mut manager = match AudioManager::new() {
-        Ok(m) => m,
+// Seek within the track loaded on `sink`. Positions past the end land on the
+// end of the track.
+fn seek_sink(sink: &Sink, state: &AudioState, seconds: f32) -> Result<f32, SeekError> {
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(SeekError::InvalidPosition(seconds));
+    }
+    if state.file_path.is_none() || sink.empty() {
+        return Err(SeekError::NothingLoaded);
+    }
+    let target = if state.duration > 0.0 { seconds.min(state.duration) } else { seconds };
+    match sink.try_seek(Duration::from_secs_f32(target)) {
+        Ok(()) => Ok(target),
+        Err(e @ rodio::source::SeekError::NotSupported { .. }) => Err(SeekError::Unsupported(e.to_string())),
+        Err(e) => Err(SeekError::Failed(e.to_string())),
+    }
+}
+
+type TrackSource = Stretch<Track<SamplesConverter<Decoder<BufReader<File>>, f32>>>;
+
+fn open_track(
+    file_path: &str,
+    fade_in: Duration,
+    speed_control: &Arc<SpeedControl>,
+) -> Result<(TrackSource, f32, Arc<TrackControl>), String> {
+    check_playable(file_path)?;
+    let file = File::open(file_path)
+        .map_err(|e| format!("Failed to open file {}: {}", file_path, e))?;
+    let source = Decoder::new(BufReader::new(file))
+        .map_err(|e| format!("Failed to decode audio: {}", e))?;
+    let duration = source_duration(file_path);
+    let (track, control) = Track::new(source.convert_samples(), fade_in);
+    Ok((Stretch::new(track, speed_control.clone()), duration, control))
+}
+
+// Read from the file with symphonia. Rodio's symphonia decoder reports a
+// `total_duration` that drops the fraction of a second (and is seconds off
+// when there is none), so it's never used.
+fn source_duration(file_path: &str) -> f32 {
+    match analysis::probe_duration(Path::new(file_path)) {
+        Ok(seconds) => seconds as f32,
         Err(e) => {
-            eprintln!("Failed to create AudioManager: {}", e);
+            eprintln!("Could not determine duration of {}: {}", file_path, e);
+            0.0
+        }
+    }
+}
+
+fn audio_thread(receiver: Receiver<AudioMessage>) {
+    // The saved device is needed to open the output, before any message.
+    let preferred_device = db::get_setting(db::SETTING_OUTPUT_DEVICE).unwrap_or_else(|e| {
+        eprintln!("Failed to read the output device setting: {}", e);
+        None
+    });
+    let Some(mut manager) = wait_for_output(&receiver, preferred_device) else { return };
+
+    loop {
+        let message = match receiver.recv_timeout(manager.wake_interval()) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => {
+                manager.poll();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        manager.handle(message);
+        manager.poll();
+    }
+}
+
+// With no output device at all there's nothing to play on, so keep trying to
+// open one. Settings sent in the meantime are applied once it's open and
+// everything else fails straight away. Returns None when the app shuts down.
+fn wait_for_output(receiver: &Receiver<AudioMessage>, preferred_device: Option<String>) -> Option<AudioManager> {
+    let mut pending = Vec::new();
+    let mut reported = false;
+    loop {
+        match AudioManager::new(preferred_device.clone()) {
+            Ok(mut manager) => {
+                for message in pending {
+                    manager.handle(message);
+                }
+                return Some(manager);
+            }
+            Err(e) if !reported => {
+                eprintln!("Failed to create AudioManager: {}", e);
+                emit(PlaybackEvent::Error { message: e });
+                reported = true;
+            }
+            Err(_) => {}
+        }
+        match receiver.recv_timeout(DEVICE_CHECK_INTERVAL) {
+            Ok(message) if message.is_setting() => pending.push(message),
+            // Dropping the message drops any reply channel, which fails the
+            // caller waiting on it.
+            Ok(_) => emit(PlaybackEvent::Error { message: "No audio output device available".to_string() }),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PreviewMessage {
+    Play { entry: QueueEntry, reply: Sender<Result<(), String>> },
+    Pause,
+    Resume,
+    Stop,
+    Seek { seconds: f32, reply: Sender<Result<f32, SeekError>> },
+    SetVolume(f32),
+    SetOutputDevice { name: Option<String>, reply: Sender<Result<String, String>> },
+    GetState(Sender<AudioState>),
+}
+
+struct PreviewOutput {
+    _stream: OutputStream,
+    sink: Sink,
+}
+
+// A single-track player of its own, on its own device. It shares nothing
+// with the main player but the library, so neither can interrupt the other.
+struct PreviewPlayer {
+    // Opened on first use, so a missing headphone device only matters once
+    // someone tries to preview.
+    output: Option<PreviewOutput>,
+    preferred_device: Option<String>,
+    state: AudioState,
+    current: Option<Arc<TrackControl>>,
+    // Previews always play at the original speed.
+    speed_control: Arc<SpeedControl>,
+    volume: f32,
+    last_tick: Instant,
+    device_watch: DeviceWatch,
+}
+
+impl PreviewPlayer {
+    fn new(preferred_device: Option<String>) -> Self {
+        PreviewPlayer {
+            output: None,
+            preferred_device,
+            state: AudioState::default(),
+            current: None,
+            speed_control: Arc::new(SpeedControl::default()),
+            volume: 1.0,
+            last_tick: Instant::now(),
+            device_watch: DeviceWatch::new(),
+        }
+    }
+
+    fn open(&mut self, name: Option<&str>) -> Result<(), String> {
+        let (stream, stream_handle, name) = open_stream(name)?;
+        let sink = Sink::try_new(&stream_handle)
+            .map_err(|e| format!("Failed to create audio sink: {}", e))?;
+        sink.set_volume(self.volume);
+        let device = device_label(name.as_deref());
+        println!("Previewing audio on {}", device);
+        self.output = Some(PreviewOutput { _stream: stream, sink });
+        self.device_watch.active = name;
+        self.state.output_device = device.clone();
+        emit_to(PREVIEW_EVENT, PlaybackEvent::DeviceChanged { device });
+        Ok(())
+    }
+
+    fn sink(&mut self) -> Result<&Sink, String> {
+        if self.output.is_none() {
+            let preferred = self.preferred_device.clone();
+            if let Err(e) = self.open(preferred.as_deref()) {
+                if preferred.is_none() {
+                    return Err(e);
+                }
+                eprintln!("{}, previewing on the default device", e);
+                self.open(None)?;
+            }
+        }
+        Ok(&self.output.as_ref().unwrap().sink)
+    }
+
+    fn position(&self) -> f32 {
+        self.current.as_ref().map_or(0.0, |current| current.position().as_secs_f32())
+    }
+
+    fn play(&mut self, entry: QueueEntry) -> Result<(), String> {
+        let (track, duration, control) = open_track(&entry.file_path, Duration::ZERO, &self.speed_control)?;
+        let sink = self.sink()?;
+        sink.clear();
+        sink.append(track);
+        sink.play();
+        self.state.duration = duration;
+        self.state.file_path = Some(entry.file_path.clone());
+        self.state.beat_id = entry.beat_id;
+        self.current = Some(control);
+        emit_to(PREVIEW_EVENT, PlaybackEvent::Started { file_path: entry.file_path, beat_id: entry.beat_id, duration });
+        Ok(())
+    }
+
+    fn pause(&self) {
+        if let Some(output) = &self.output {
+            output.sink.pause();
+            emit_to(PREVIEW_EVENT, PlaybackEvent::Paused { pos: self.position() });
+        }
+    }
+
+    fn resume(&self) {
+        if let Some(output) = &self.output {
+            output.sink.play();
+            emit_to(PREVIEW_EVENT, PlaybackEvent::Resumed { pos: self.position() });
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(output) = &self.output {
+            output.sink.clear();
+        }
+        self.unload();
+        emit_to(PREVIEW_EVENT, PlaybackEvent::Stopped);
+    }
+
+    fn unload(&mut self) {
+        self.state = AudioState { output_device: std::mem::take(&mut self.state.output_device), ..AudioState::default() };
+        self.current = None;
+    }
+
+    fn seek(&self, seconds: f32) -> Result<f32, SeekError> {
+        let output = self.output.as_ref().ok_or(SeekError::NothingLoaded)?;
+        let target = seek_sink(&output.sink, &self.state, seconds)?;
+        emit_to(PREVIEW_EVENT, PlaybackEvent::Seeked { pos: target });
+        Ok(target)
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        if let Some(output) = &self.output {
+            output.sink.set_volume(volume);
+        }
+    }
+
+    // Move to another device, carrying on from the same position.
+    fn set_output_device(&mut self, name: Option<String>) -> Result<String, String> {
+        self.switch_output(name.as_deref())?;
+        self.preferred_device = name;
+        Ok(self.state.output_device.clone())
+    }
+
+    fn switch_output(&mut self, name: Option<&str>) -> Result<(), String> {
+        let resume = self.state.file_path.clone().map(|file_path| {
+            let paused = self.output.as_ref().is_some_and(|output| output.sink.is_paused());
+            (QueueEntry { beat_id: self.state.beat_id, file_path }, self.position(), paused)
+        });
+        if let Some(output) = self.output.take() {
+            output.sink.clear();
+        }
+        self.open(name)?;
+        if let Some((entry, pos, paused)) = resume {
+            if let Err(e) = self.play(entry) {
+                eprintln!("Error reopening preview: {}", e);
+                emit_to(PREVIEW_EVENT, PlaybackEvent::Error { message: e });
+                self.unload();
+                return Ok(());
+            }
+            if paused {
+                self.pause();
+            }
+            if let Err(e) = self.seek(pos) {
+                eprintln!("Failed to restore preview position: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    // Same device handling as the main player: fall back to the default when
+    // the device goes away, and return to the preferred one when it's back.
+    fn check_output_device(&mut self) {
+        if self.output.is_none() {
             return;
         }
-    };
+        let Some(target) = self.device_watch.check(self.preferred_device.as_deref()) else {
+            return;
+        };
+        if let Err(e) = self.switch_output(target.as_deref()) {
+            eprintln!("Failed to switch preview device: {}", e);
+            emit_to(PREVIEW_EVENT, PlaybackEvent::Error { message: e });
+        }
+    }
+
+    fn poll(&mut self) {
+        self.check_output_device();
+        let Some(output) = &self.output else { return };
+        if output.sink.empty() {
+            if let Some(file_path) = self.state.file_path.clone() {
+                let beat_id = self.state.beat_id;
+                self.unload();
+                emit_to(PREVIEW_EVENT, PlaybackEvent::Ended { file_path, beat_id });
+            }
+            return;
+        }
+        if output.sink.is_paused() || self.last_tick.elapsed() < Duration::from_secs_f32(1.0 / DEFAULT_TICK_RATE) {
+            return;
+        }
+        self.last_tick = Instant::now();
+        emit_to(PREVIEW_EVENT, PlaybackEvent::Position {
+            pos: self.position(),
+            duration: self.state.duration,
+            beat_id: self.state.beat_id,
+        });
+    }
+
+    fn get_state(&mut self) -> AudioState {
+        self.state.pos = self.position();
+        self.state.is_playing = self
+            .output
+            .as_ref()
+            .is_some_and(|output| !output.sink.is_paused() && !output.sink.empty());
+        self.state.clone()
+    }
 
-    for message in receiver {
+    fn handle(&mut self, message: PreviewMessage) {
         match message {
-            AudioMessage::Play(path) => {
-                if let Err(e) = manager.play(&path) {
-                    eprintln!("Error playing audio: {}", e);
+            PreviewMessage::Play { entry, reply } => {
+                if reply.send(self.play(entry)).is_err() {
+                    eprintln!("Failed to send preview result");
                 }
-            },
-            AudioMessage::Pause => manager.pause(),
-            AudioMessage::Resume => manager.play_sink(),
-            AudioMessage::Stop => manager.stop(),
-            AudioMessage::SetVolume(volume) => manager.set_volume(volume),
-            AudioMessage::GetState(sender) => {
-                let state: AudioState = manager.get_state();
-                if sender.send(state).is_err() {
-                    eprintln!("Failed to send audio state");
+            }
+            PreviewMessage::Pause => self.pause(),
+            PreviewMessage::Resume => self.resume(),
+            PreviewMessage::Stop => self.stop(),
+            PreviewMessage::Seek { seconds, reply } => {
+                if reply.send(self.seek(seconds)).is_err() {
+                    eprintln!("Failed to send seek result");
                 }
             }
-            AudioMessage::Seek(seconds) => {
-                manager.seek(seconds);
+            PreviewMessage::SetVolume(volume) => self.set_volume(volume),
+            PreviewMessage::SetOutputDevice { name, reply } => {
+                if reply.send(self.set_output_device(name)).is_err() {
+                    eprintln!("Failed to send output device result");
+                }
+            }
+            PreviewMessage::GetState(sender) => {
+                if sender.send(self.get_state()).is_err() {
+                    eprintln!("Failed to send preview state");
+                }
             }
         }
     }
 }
 
+fn preview_thread(receiver: Receiver<PreviewMessage>) {
+    let preferred_device = db::get_setting(db::SETTING_PREVIEW_DEVICE).unwrap_or_else(|e| {
+        eprintln!("Failed to read the preview device setting: {}", e);
+        None
+    });
+    let mut player = PreviewPlayer::new(preferred_device);
+    let tick_interval = Duration::from_secs_f32(1.0 / DEFAULT_TICK_RATE);
+    loop {
+        match receiver.recv_timeout(tick_interval) {
+            Ok(message) => player.handle(message),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        player.poll();
+    }
+}
+
+fn emit(event: PlaybackEvent) {
+    emit_to(PLAYBACK_EVENT, event);
+}
+
+fn emit_to(name: &str, event: PlaybackEvent) {
+    let Some(app_handle) = APP_HANDLE.get() else { return };
+    if let Err(e) = app_handle.emit_all(name, event) {
+        eprintln!("Failed to emit {} event: {}", name, e);
+    }
+}
+
+// Route playback events to the frontend and apply the saved playback settings.
+pub fn init(app_handle: AppHandle, tick_rate: f32, transition: Transition, normalization: Normalization) -> Result<(), String> {
+    APP_HANDLE
+        .set(app_handle)
+        .map_err(|_| "Audio events are already initialized".to_string())?;
+    set_tick_rate(tick_rate)?;
+    set_transition(transition)?;
+    set_normalization(normalization)
+}
+
+// Catch files that moved since the last scan before they reach the sink, and
+// flag them so they can be relocated.
+fn check_playable(file_path: &str) -> Result<(), String> {
+    if Path::new(file_path).is_file() {
+        return Ok(());
+    }
+    if let Err(e) = db::set_missing_by_path(file_path, true) {
+        eprintln!("Failed to flag {} as missing: {}", file_path, e);
+    }
+    Err(format!("File not found: {}", file_path))
+}
+
+fn queue_entries(beat_ids: &[u32]) -> Result<Vec<QueueEntry>, String> {
+    beat_ids
+        .iter()
+        .map(|&beat_id| {
+            let beat = db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+            Ok(QueueEntry { beat_id: Some(beat_id), file_path: beat.file_path().to_string() })
+        })
+        .collect()
+}
+
+// Replace the queue with this one beat and play it.
 pub fn play_beat(file_path: String) -> Result<(), String> {
+    check_playable(&file_path)?;
+    let beat_id = match db::find_beat_by_path(&file_path) {
+        Ok(beat) => beat.map(|(beat_id, _)| beat_id),
+        Err(e) => {
+            eprintln!("Failed to look up beat for {}: {}", file_path, e);
+            None
+        }
+    };
     AUDIO_SENDER
-        .send(AudioMessage::Play(file_path))
+        .send(AudioMessage::Play { entries: vec![QueueEntry { beat_id, file_path }], start: 0 })
         .map_err(|e| format!("Failed to send play message: {}", e))
 }
 
+// Queue up every beat in a set, in set order, and start playing at
+// `start_beat_id` (or the top of the set). Beats flagged missing are left out.
+pub fn play_set(set_id: u32, start_beat_id: Option<u32>) -> Result<(), String> {
+    let beats = db::get_beats_in_set(set_id).map_err(|e| e.to_string())?;
+    let entries: Vec<QueueEntry> = beats
+        .iter()
+        .filter(|beat| !beat.is_missing())
+        .map(|beat| QueueEntry { beat_id: Some(beat.id()), file_path: beat.file_path().to_string() })
+        .collect();
+    if entries.is_empty() {
+        return Err("There are no playable beats in this set".to_string());
+    }
+    let start = start_beat_id
+        .and_then(|beat_id| entries.iter().position(|entry| entry.beat_id == Some(beat_id)))
+        .unwrap_or(0);
+    AUDIO_SENDER
+        .send(AudioMessage::Play { entries, start })
+        .map_err(|e| format!("Failed to send play message: {}", e))
+}
+
+pub fn enqueue(beat_ids: Vec<u32>) -> Result<(), String> {
+    let entries = queue_entries(&beat_ids)?;
+    AUDIO_SENDER
+        .send(AudioMessage::Enqueue(entries))
+        .map_err(|e| format!("Failed to send enqueue message: {}", e))
+}
+
+pub fn play_next(beat_ids: Vec<u32>) -> Result<(), String> {
+    let entries = queue_entries(&beat_ids)?;
+    AUDIO_SENDER
+        .send(AudioMessage::PlayNext(entries))
+        .map_err(|e| format!("Failed to send play_next message: {}", e))
+}
+
+pub fn skip() -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::Skip)
+        .map_err(|e| format!("Failed to send skip message: {}", e))
+}
+
+pub fn previous() -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::Previous)
+        .map_err(|e| format!("Failed to send previous message: {}", e))
+}
+
+pub fn clear_queue() -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::ClearQueue)
+        .map_err(|e| format!("Failed to send clear_queue message: {}", e))
+}
+
+pub fn move_in_queue(from: usize, to: usize) -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::MoveInQueue { from, to })
+        .map_err(|e| format!("Failed to send move_in_queue message: {}", e))
+}
+
+pub fn get_queue() -> Result<PlayQueue, String> {
+    let (sender, receiver) = channel();
+    AUDIO_SENDER
+        .send(AudioMessage::GetQueue(sender))
+        .map_err(|e| format!("Failed to send get_queue message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive play queue: {}", e))
+}
+
 pub fn pause() -> Result<(), String> {
     AUDIO_SENDER
         .send(AudioMessage::Pause)
@@ -159,6 +1462,29 @@ pub fn set_volume(volume: f32) -> Result<(), String> {
         .map_err(|e| format!("Failed to send set_volume message: {}", e))
 }
 
+// Rates outside 1 to 60 ticks a second are clamped.
+pub fn set_tick_rate(rate: f32) -> Result<(), String> {
+    if !rate.is_finite() {
+        return Err(format!("Invalid tick rate: {}", rate));
+    }
+    AUDIO_SENDER
+        .send(AudioMessage::SetTickRate(rate))
+        .map_err(|e| format!("Failed to send set_tick_rate message: {}", e))
+}
+
+pub fn set_transition(transition: Transition) -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::SetTransition(transition))
+        .map_err(|e| format!("Failed to send set_transition message: {}", e))
+}
+
+pub fn set_normalization(normalization: Normalization) -> Result<(), String> {
+    normalization.check()?;
+    AUDIO_SENDER
+        .send(AudioMessage::SetNormalization(normalization))
+        .map_err(|e| format!("Failed to send set_normalization message: {}", e))
+}
+
 pub fn get_state() -> Result<AudioState, String> {
     let (sender, receiver) = channel();
     AUDIO_SENDER
@@ -167,10 +1493,251 @@ pub fn get_state() -> Result<AudioState, String> {
     receiver.recv().map_err(|e| format!("Failed to receive audio state: {}", e))
 }
 
-pub fn seek(seconds: f32) -> Result<(), String> {
-    let (_sender, receiver) = channel();
+// Returns the position playback actually resumed from.
+pub fn seek(seconds: f32) -> Result<f32, SeekError> {
+    let (reply, receiver) = channel();
     AUDIO_SENDER
-        .send(AudioMessage::Seek(seconds))
-        .map_err(|e| format!("Failed to send seek message: {}", e))?;
-    receiver.recv().map_err(|e| format!("Failed to receive audio state: {}", e))
-}
\ No newline at end of file
+        .send(AudioMessage::Seek { seconds, reply })
+        .map_err(|e| SeekError::Unavailable(format!("Failed to send seek message: {}", e)))?;
+    receiver
+        .recv_timeout(SEEK_TIMEOUT)
+        .map_err(|e| SeekError::Unavailable(format!("Failed to receive seek result: {}", e)))?
+}
+
+pub fn output_devices() -> Result<Vec<OutputDevice>, String> {
+    let default = rodio::cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let active = get_state()?.output_device;
+    Ok(output_device_names()?
+        .into_iter()
+        .map(|name| OutputDevice {
+            is_default: default.as_ref() == Some(&name),
+            is_active: name == active,
+            name,
+        })
+        .collect())
+}
+
+// Switch to the named device, or the system default with None. Returns the
+// device now in use.
+pub fn set_output_device(name: Option<String>) -> Result<String, String> {
+    let (reply, receiver) = channel();
+    AUDIO_SENDER
+        .send(AudioMessage::SetOutputDevice { name, reply })
+        .map_err(|e| format!("Failed to send set_output_device message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive output device: {}", e))?
+}
+
+// Speeds outside 0.5x to 2x are clamped.
+pub fn set_playback_rate(rate: f32) -> Result<(), String> {
+    if !rate.is_finite() {
+        return Err(format!("Invalid playback rate: {}", rate));
+    }
+    AUDIO_SENDER
+        .send(AudioMessage::SetPlaybackRate(rate))
+        .map_err(|e| format!("Failed to send set_playback_rate message: {}", e))
+}
+
+pub fn set_speed_mode(mode: SpeedMode) -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::SetSpeedMode(mode))
+        .map_err(|e| format!("Failed to send set_speed_mode message: {}", e))
+}
+
+// Shifts of more than an octave either way are clamped.
+pub fn set_pitch_shift(semitones: f32) -> Result<(), String> {
+    if !semitones.is_finite() {
+        return Err(format!("Invalid pitch shift: {}", semitones));
+    }
+    AUDIO_SENDER
+        .send(AudioMessage::SetPitchShift(semitones))
+        .map_err(|e| format!("Failed to send set_pitch_shift message: {}", e))
+}
+
+// Returns the position playback resumed from.
+pub fn jump_to_cue(cue_id: i64) -> Result<f32, String> {
+    let (reply, receiver) = channel();
+    AUDIO_SENDER
+        .send(AudioMessage::JumpToCue { cue_id, reply })
+        .map_err(|e| format!("Failed to send jump_to_cue message: {}", e))?;
+    receiver
+        .recv_timeout(SEEK_TIMEOUT)
+        .map_err(|e| format!("Failed to receive seek result: {}", e))?
+}
+
+// Loop the loaded track between two points, given in seconds or bars.
+pub fn set_loop(start: f32, end: f32, unit: LoopUnit) -> Result<LoopPoints, String> {
+    let (reply, receiver) = channel();
+    AUDIO_SENDER
+        .send(AudioMessage::SetLoop { start, end, unit, reply })
+        .map_err(|e| format!("Failed to send set_loop message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive loop result: {}", e))?
+}
+
+// Returns whether the loop is on afterwards.
+pub fn toggle_loop() -> Result<bool, String> {
+    let (reply, receiver) = channel();
+    AUDIO_SENDER
+        .send(AudioMessage::ToggleLoop(reply))
+        .map_err(|e| format!("Failed to send toggle_loop message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive loop result: {}", e))?
+}
+
+pub fn clear_loop() -> Result<(), String> {
+    AUDIO_SENDER
+        .send(AudioMessage::ClearLoop)
+        .map_err(|e| format!("Failed to send clear_loop message: {}", e))
+}
+
+// Save loop points as a named region of a beat, whether or not it's loaded.
+pub fn save_loop_region(beat_id: u32, name: &str, start: f32, end: f32, unit: LoopUnit) -> Result<i64, String> {
+    let beat = db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    let duration = match analysis::probe_duration(Path::new(beat.file_path())) {
+        Ok(seconds) => seconds as f32,
+        Err(e) => {
+            eprintln!("Could not determine duration of {}: {}", beat.file_path(), e);
+            0.0
+        }
+    };
+    let (start, end) = loop_seconds(start, end, unit, Some(beat.bpm() as f32), duration)?;
+    db::add_loop_region(beat_id, name, start, end).map_err(|e| e.to_string())
+}
+
+// Start looping a saved region. Its beat has to be the one loaded.
+pub fn activate_loop_region(region_id: i64) -> Result<LoopPoints, String> {
+    let region = db::get_loop_region(region_id).map_err(|e| format!("Loop region {} not found: {}", region_id, e))?;
+    if get_state()?.beat_id != Some(region.beat_id) {
+        return Err(format!("Load beat {} to play loop \"{}\"", region.beat_id, region.name));
+    }
+    set_loop(region.start_seconds, region.end_seconds, LoopUnit::Seconds)
+}
+
+// Play a beat on the preview output, replacing whatever was previewing.
+pub fn preview_beat(beat_id: u32) -> Result<(), String> {
+    let entry = queue_entries(&[beat_id])?.remove(0);
+    let (reply, receiver) = channel();
+    PREVIEW_SENDER
+        .send(PreviewMessage::Play { entry, reply })
+        .map_err(|e| format!("Failed to send preview message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive preview result: {}", e))?
+}
+
+pub fn pause_preview() -> Result<(), String> {
+    PREVIEW_SENDER
+        .send(PreviewMessage::Pause)
+        .map_err(|e| format!("Failed to send preview pause message: {}", e))
+}
+
+pub fn resume_preview() -> Result<(), String> {
+    PREVIEW_SENDER
+        .send(PreviewMessage::Resume)
+        .map_err(|e| format!("Failed to send preview resume message: {}", e))
+}
+
+pub fn stop_preview() -> Result<(), String> {
+    PREVIEW_SENDER
+        .send(PreviewMessage::Stop)
+        .map_err(|e| format!("Failed to send preview stop message: {}", e))
+}
+
+pub fn seek_preview(seconds: f32) -> Result<f32, SeekError> {
+    let (reply, receiver) = channel();
+    PREVIEW_SENDER
+        .send(PreviewMessage::Seek { seconds, reply })
+        .map_err(|e| SeekError::Unavailable(format!("Failed to send preview seek message: {}", e)))?;
+    receiver
+        .recv_timeout(SEEK_TIMEOUT)
+        .map_err(|e| SeekError::Unavailable(format!("Failed to receive seek result: {}", e)))?
+}
+
+pub fn set_preview_volume(volume: f32) -> Result<(), String> {
+    PREVIEW_SENDER
+        .send(PreviewMessage::SetVolume(volume))
+        .map_err(|e| format!("Failed to send preview volume message: {}", e))
+}
+
+// Returns the device the preview plays on now.
+pub fn set_preview_device(name: Option<String>) -> Result<String, String> {
+    let (reply, receiver) = channel();
+    PREVIEW_SENDER
+        .send(PreviewMessage::SetOutputDevice { name, reply })
+        .map_err(|e| format!("Failed to send preview device message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive preview device: {}", e))?
+}
+
+pub fn get_preview_state() -> Result<AudioState, String> {
+    let (sender, receiver) = channel();
+    PREVIEW_SENDER
+        .send(PreviewMessage::GetState(sender))
+        .map_err(|e| format!("Failed to send preview get_state message: {}", e))?;
+    receiver.recv().map_err(|e| format!("Failed to receive preview state: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(len: usize, current: Option<usize>) -> PlayQueue {
+        let entries = (0..len)
+            .map(|index| QueueEntry { beat_id: Some(index as u32), file_path: format!("{}.wav", index) })
+            .collect();
+        PlayQueue { entries, current }
+    }
+
+    fn beat_ids(queue: &PlayQueue) -> Vec<u32> {
+        queue.entries.iter().map(|entry| entry.beat_id.unwrap()).collect()
+    }
+
+    #[test]
+    fn moving_entries_keeps_current_on_the_same_entry() {
+        for current in 0..5 {
+            for from in 0..5 {
+                for to in 0..5 {
+                    let mut queue = queue(5, Some(current));
+                    queue.move_entry(from, to).unwrap();
+                    let index = queue.current.unwrap();
+                    assert_eq!(queue.entries[index].beat_id, Some(current as u32), "{} -> {} around {}", from, to, current);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moving_entries_around_current() {
+        // Later entry to before the current one.
+        let mut before = queue(5, Some(2));
+        before.move_entry(4, 0).unwrap();
+        assert_eq!(beat_ids(&before), [4, 0, 1, 2, 3]);
+        assert_eq!(before.current, Some(3));
+        assert_eq!(before.next_index(), 4);
+
+        // Earlier entry to after the current one.
+        let mut after = queue(5, Some(2));
+        after.move_entry(0, 3).unwrap();
+        assert_eq!(beat_ids(&after), [1, 2, 3, 0, 4]);
+        assert_eq!(after.current, Some(1));
+
+        // The current entry itself.
+        let mut moved = queue(5, Some(2));
+        moved.move_entry(2, 4).unwrap();
+        assert_eq!(beat_ids(&moved), [0, 1, 3, 4, 2]);
+        assert_eq!(moved.current, Some(4));
+
+        // Nothing loaded yet.
+        let mut idle = queue(3, None);
+        idle.move_entry(0, 2).unwrap();
+        assert_eq!(beat_ids(&idle), [1, 2, 0]);
+        assert_eq!(idle.current, None);
+        assert_eq!(idle.next_index(), 0);
+    }
+
+    #[test]
+    fn moving_out_of_range_changes_nothing() {
+        let mut queue = queue(3, Some(1));
+        assert!(queue.move_entry(3, 0).is_err());
+        assert!(queue.move_entry(0, 3).is_err());
+        assert_eq!(beat_ids(&queue), [0, 1, 2]);
+        assert_eq!(queue.current, Some(1));
+    }
+}
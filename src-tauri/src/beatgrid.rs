@@ -0,0 +1,264 @@
+use std::path::Path;
+
+use crate::analysis::{self, DecodedAudio, BEATS_PER_BAR};
+use crate::db::{self, Beat, Beatgrid};
+
+// Halving or doubling stops at tempos no track plausibly has.
+const MIN_GRID_BPM: f64 = 30.0;
+const MAX_GRID_BPM: f64 = 400.0;
+
+// A grid laid out over the whole track, for the frontend.
+#[derive(serde::Serialize)]
+pub struct GridLayout {
+    beat_id: u32,
+    bpm: f64,
+    first_downbeat: f64,
+    // Whether the grid keeps one tempo throughout, rather than following
+    // drift.
+    constant: bool,
+    edited: bool,
+    beats: Vec<f64>,
+    downbeats: Vec<f64>,
+}
+
+impl GridLayout {
+    fn new(grid: &Beatgrid) -> Self {
+        let beats = positions(grid);
+        let anchor = nearest_beat(&beats, grid.first_downbeat).unwrap_or(0);
+        let downbeats = beats
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (*i as isize - anchor as isize).rem_euclid(BEATS_PER_BAR as isize) == 0)
+            .map(|(_, &beat)| beat)
+            .collect();
+        GridLayout {
+            beat_id: grid.beat_id,
+            bpm: grid.bpm,
+            first_downbeat: grid.first_downbeat,
+            constant: grid.beats.is_none(),
+            edited: grid.edited,
+            beats,
+            downbeats,
+        }
+    }
+}
+
+// Every beat in the track. A constant grid runs back from its downbeat to the
+// start of the track and on to the end.
+fn positions(grid: &Beatgrid) -> Vec<f64> {
+    if let Some(beats) = &grid.beats {
+        return beats.clone();
+    }
+    let period = 60.0 / grid.bpm;
+    let first = grid.first_downbeat - (grid.first_downbeat / period).floor() * period;
+    (0..)
+        .map(|i| first + i as f64 * period)
+        .take_while(|beat| *beat < grid.duration)
+        .collect()
+}
+
+fn nearest_beat(beats: &[f64], position: f64) -> Option<usize> {
+    (0..beats.len()).min_by(|&a, &b| (beats[a] - position).abs().total_cmp(&(beats[b] - position).abs()))
+}
+
+// A beat's grid, detecting it the first time it's asked for.
+pub fn get_beatgrid(beat_id: u32) -> Result<GridLayout, String> {
+    load(beat_id).map(|grid| GridLayout::new(&grid))
+}
+
+// Detect the grid again, throwing away any edits.
+pub fn reanalyze_beatgrid(beat_id: u32) -> Result<GridLayout, String> {
+    detect(beat_id).map(|grid| GridLayout::new(&grid))
+}
+
+fn load(beat_id: u32) -> Result<Beatgrid, String> {
+    match db::get_beatgrid(beat_id).map_err(|e| e.to_string())? {
+        Some(grid) => Ok(grid),
+        None => detect(beat_id),
+    }
+}
+
+fn detect(beat_id: u32) -> Result<Beatgrid, String> {
+    let beat = db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    println!("Detecting beatgrid for beat {}: {}", beat_id, beat.file_path());
+    let audio = analysis::decode_file(Path::new(beat.file_path()))
+        .map_err(|e| format!("Failed to decode {}: {}", beat.file_path(), e))?;
+    let grid = estimate(&beat, &audio).ok_or_else(|| format!("No beats detected in {}", beat.file_path()))?;
+    db::save_beatgrid(&grid).map_err(|e| e.to_string())?;
+    Ok(grid)
+}
+
+// A fresh grid for a beat's decoded audio, tracked at its stored BPM when it
+// has one so a tempo corrected by hand isn't detected back to half or double.
+pub fn estimate(beat: &Beat, audio: &DecodedAudio) -> Option<Beatgrid> {
+    let mono = audio.to_mono();
+    let bpm_hint = Some(beat.bpm() as f32).filter(|bpm| *bpm > 0.0);
+    let estimate = analysis::estimate_beatgrid(&mono, audio.sample_rate, bpm_hint)?;
+    Some(Beatgrid {
+        beat_id: beat.id(),
+        bpm: estimate.bpm,
+        first_downbeat: estimate.first_downbeat,
+        duration: mono.len() as f64 / audio.sample_rate.max(1) as f64,
+        beats: estimate.beats,
+        edited: false,
+    })
+}
+
+fn edit_grid(beat_id: u32, change: impl FnOnce(&mut Beatgrid) -> Result<(), String>) -> Result<GridLayout, String> {
+    let mut grid = load(beat_id)?;
+    change(&mut grid)?;
+    grid.edited = true;
+    db::save_beatgrid(&grid).map_err(|e| e.to_string())?;
+    Ok(GridLayout::new(&grid))
+}
+
+// Move the whole grid earlier (negative) or later (positive).
+pub fn nudge_beatgrid(beat_id: u32, seconds: f64) -> Result<GridLayout, String> {
+    if !seconds.is_finite() {
+        return Err(format!("Can't nudge a grid by {} seconds", seconds));
+    }
+    edit_grid(beat_id, |grid| {
+        grid.first_downbeat += seconds;
+        if let Some(beats) = &mut grid.beats {
+            beats.iter_mut().for_each(|beat| *beat += seconds);
+        }
+        Ok(())
+    })
+}
+
+// Fix a grid detected at double the real tempo. Every other beat is dropped,
+// keeping the downbeats where they were.
+pub fn halve_beatgrid(beat_id: u32) -> Result<GridLayout, String> {
+    edit_grid(beat_id, halve)
+}
+
+fn halve(grid: &mut Beatgrid) -> Result<(), String> {
+    let bpm = scaled_bpm(grid, 0.5)?;
+    if let Some(beats) = &mut grid.beats {
+        let anchor = nearest_beat(beats, grid.first_downbeat).unwrap_or(0);
+        *beats = beats.iter().skip(anchor % 2).step_by(2).copied().collect();
+    }
+    grid.bpm = bpm;
+    Ok(())
+}
+
+// Fix a grid detected at half the real tempo, adding a beat halfway between
+// each pair.
+pub fn double_beatgrid(beat_id: u32) -> Result<GridLayout, String> {
+    edit_grid(beat_id, double)
+}
+
+fn double(grid: &mut Beatgrid) -> Result<(), String> {
+    let bpm = scaled_bpm(grid, 2.0)?;
+    if let Some(beats) = &mut grid.beats {
+        let mut doubled = Vec::with_capacity(beats.len() * 2);
+        for pair in beats.windows(2) {
+            doubled.extend([pair[0], (pair[0] + pair[1]) / 2.0]);
+        }
+        doubled.extend(beats.last());
+        *beats = doubled;
+    }
+    grid.bpm = bpm;
+    Ok(())
+}
+
+fn scaled_bpm(grid: &Beatgrid, factor: f64) -> Result<f64, String> {
+    let bpm = grid.bpm * factor;
+    if !(MIN_GRID_BPM..=MAX_GRID_BPM).contains(&bpm) {
+        return Err(format!("A grid can't go to {:.1} BPM", bpm));
+    }
+    Ok(bpm)
+}
+
+// Make `position` a downbeat, shifting the grid to line up with it and
+// keeping its tempo.
+pub fn set_beatgrid_anchor(beat_id: u32, position: f64) -> Result<GridLayout, String> {
+    edit_grid(beat_id, |grid| {
+        if !position.is_finite() || position < 0.0 || position > grid.duration {
+            return Err(format!("Can't anchor the grid at {} seconds", position));
+        }
+        if let Some(beats) = &mut grid.beats {
+            if let Some(nearest) = nearest_beat(beats, position) {
+                let offset = position - beats[nearest];
+                beats.iter_mut().for_each(|beat| *beat += offset);
+            }
+        }
+        grid.first_downbeat = position;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant_grid(bpm: f64, first_downbeat: f64) -> Beatgrid {
+        Beatgrid { beat_id: 1, bpm, first_downbeat, duration: 60.0, beats: None, edited: false }
+    }
+
+    // Beats half a second apart with the downbeat on the second one.
+    fn drifting_grid() -> Beatgrid {
+        let beats: Vec<f64> = (0..16).map(|i| 0.25 + i as f64 * 0.5).collect();
+        Beatgrid { beat_id: 1, bpm: 120.0, first_downbeat: beats[1], duration: 8.5, beats: Some(beats), edited: false }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn halving_keeps_the_downbeat() {
+        let mut grid = drifting_grid();
+        halve(&mut grid).unwrap();
+        assert_eq!(grid.bpm, 60.0);
+        assert_eq!(grid.first_downbeat, 0.75);
+        let beats = grid.beats.as_ref().unwrap();
+        assert_eq!(beats.len(), 8);
+        assert!(beats.contains(&0.75));
+        assert!(beats.windows(2).all(|pair| (pair[1] - pair[0] - 1.0).abs() < 1e-9));
+        assert_eq!(GridLayout::new(&grid).downbeats[0], 0.75);
+
+        let mut constant = constant_grid(128.0, 1.3);
+        halve(&mut constant).unwrap();
+        assert_eq!(constant.bpm, 64.0);
+        assert!(GridLayout::new(&constant).downbeats.iter().any(|&downbeat| (downbeat - 1.3).abs() < 1e-9));
+    }
+
+    #[test]
+    fn doubling_keeps_the_downbeat() {
+        let mut grid = drifting_grid();
+        double(&mut grid).unwrap();
+        assert_eq!(grid.bpm, 240.0);
+        let beats = grid.beats.as_ref().unwrap();
+        assert_eq!(beats.len(), 31);
+        assert_close(beats[1], 0.5);
+        assert_eq!(beats[2], 0.75);
+        let layout = GridLayout::new(&grid);
+        assert_eq!(layout.downbeats[0], 0.75);
+        assert_close(layout.downbeats[1], 1.75);
+
+        let mut constant = constant_grid(64.0, 1.3);
+        double(&mut constant).unwrap();
+        assert_eq!(constant.bpm, 128.0);
+        assert!(GridLayout::new(&constant).downbeats.iter().any(|&downbeat| (downbeat - 1.3).abs() < 1e-9));
+    }
+
+    #[test]
+    fn halving_then_doubling_restores_a_constant_grid() {
+        let mut grid = constant_grid(140.0, 0.4);
+        let before = GridLayout::new(&grid);
+        halve(&mut grid).unwrap();
+        double(&mut grid).unwrap();
+        let after = GridLayout::new(&grid);
+        assert_eq!(before.beats.len(), after.beats.len());
+        for (a, b) in before.beats.iter().zip(&after.beats) {
+            assert_close(*a, *b);
+        }
+    }
+
+    #[test]
+    fn scaling_stops_at_implausible_tempos() {
+        assert!(halve(&mut constant_grid(50.0, 0.0)).is_err());
+        assert!(double(&mut constant_grid(250.0, 0.0)).is_err());
+    }
+}
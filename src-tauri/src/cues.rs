@@ -0,0 +1,36 @@
+use crate::db;
+
+pub fn add_cue_point(beat_id: u32, position: f32, label: String, color: Option<String>) -> Result<i64, String> {
+    db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    let (label, color) = check_cue(position, label, color)?;
+    db::add_cue_point(beat_id, position, &label, color.as_deref()).map_err(|e| e.to_string())
+}
+
+pub fn update_cue_point(cue_id: i64, position: f32, label: String, color: Option<String>) -> Result<(), String> {
+    let (label, color) = check_cue(position, label, color)?;
+    match db::update_cue_point(cue_id, position, &label, color.as_deref()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Cue point {} not found", cue_id)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Validate a cue and tidy up its label and color for storage. Colors are
+// "#rrggbb", stored lowercase; an empty one means no color.
+fn check_cue(position: f32, label: String, color: Option<String>) -> Result<(String, Option<String>), String> {
+    if !position.is_finite() || position < 0.0 {
+        return Err(format!("Can't place a cue at {} seconds", position));
+    }
+    let label = label.trim().to_string();
+    if label.is_empty() {
+        return Err("Cue points need a label".to_string());
+    }
+    let color = color.map(|color| color.trim().to_lowercase()).filter(|color| !color.is_empty());
+    if let Some(color) = &color {
+        let hex = color.strip_prefix('#').unwrap_or("");
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("{} is not a color like #ff8800", color));
+        }
+    }
+    Ok((label, color))
+}
@@ -1,5 +1,5 @@
 use std::fs;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
 use std::env;
 use lazy_static::lazy_static;
 use std::sync::Mutex;
@@ -13,6 +13,11 @@ use symphonia::core::probe::Hint;
 use symphonia::core::units::Time;
 
 use crate::EditThisBeat;
+use crate::analysis;
+use crate::duplicates;
+use crate::jobs;
+use crate::tags;
+use crate::migrations::{self, MigrationError};
 
 
 #[derive(serde::Serialize)]
@@ -26,7 +31,46 @@ pub struct Beat {
     date_added: String,
     file_path: String,
     row_number: i32,
+    bpm_confidence: Option<f64>,
+    camelot_key: Option<String>,
+    album: Option<String>,
+    genre: Option<String>,
+    comment: Option<String>,
+    missing: bool,
+    loudness_lufs: Option<f64>,
+    true_peak_db: Option<f64>,
+    replay_gain_db: Option<f64>,
 }
+impl Beat {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn bpm(&self) -> u32 {
+        self.bpm
+    }
+
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    pub fn camelot_key(&self) -> Option<&str> {
+        self.camelot_key.as_deref()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.missing
+    }
+
+    pub fn loudness_lufs(&self) -> Option<f64> {
+        self.loudness_lufs
+    }
+
+    pub fn true_peak_db(&self) -> Option<f64> {
+        self.true_peak_db
+    }
+}
+
 #[derive(serde::Serialize)]
 pub struct ColumnVisibility {
     title: bool,
@@ -38,19 +82,147 @@ pub struct ColumnVisibility {
     file_path: bool,
 }
 
+// Everything known about a file at import time, ready to be inserted.
+pub struct NewBeat {
+    pub file_path: String,
+    pub title: String,
+    pub bpm: u32,
+    pub bpm_confidence: Option<f64>,
+    pub musical_key: String,
+    pub camelot_key: Option<String>,
+    pub duration: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub file_size: Option<i64>,
+    pub fingerprint: Option<Vec<u8>>,
+    pub content_hash: String,
+    pub loudness: Option<analysis::Loudness>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TagWriteReport {
+    beat_id: u32,
+    file_path: String,
+    dry_run: bool,
+    changes: Vec<tags::TagChange>,
+}
+
+#[derive(serde::Serialize)]
+pub struct WatchedFolder {
+    pub id: i64,
+    pub path: String,
+    pub date_added: String,
+}
+
+// A named A/B loop saved against a beat.
+#[derive(serde::Serialize)]
+pub struct LoopRegion {
+    pub id: i64,
+    pub beat_id: u32,
+    pub name: String,
+    pub start_seconds: f32,
+    pub end_seconds: f32,
+    pub date_added: String,
+}
+
+// A hot cue: a labelled position in a beat to jump straight to.
+#[derive(serde::Serialize)]
+pub struct CuePoint {
+    pub id: i64,
+    pub beat_id: u32,
+    pub position_seconds: f32,
+    pub label: String,
+    // "#rrggbb", or None for the frontend's default.
+    pub color: Option<String>,
+    pub date_added: String,
+}
+
+// An encoded waveform and the file it was made from: its contents' hash, and
+// its size and modification time to check cheaply whether it has changed.
+pub struct CachedWaveform {
+    pub content_hash: String,
+    pub file_size: Option<i64>,
+    pub file_modified: Option<i64>,
+    pub data: Vec<u8>,
+}
+
+// A beat's grid. A constant grid is described by its tempo and a downbeat;
+// one that follows tempo drift keeps every beat position.
+#[derive(Clone)]
+pub struct Beatgrid {
+    pub beat_id: u32,
+    pub bpm: f64,
+    pub first_downbeat: f64,
+    pub duration: f64,
+    pub beats: Option<Vec<f64>>,
+    // Changed by hand since it was detected.
+    pub edited: bool,
+}
+
+// A queued piece of background work on a beat. `kind` and `status` are the
+// names `jobs` uses for them.
+#[derive(serde::Serialize, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub beat_id: u32,
+    pub kind: String,
+    pub priority: i64,
+    pub status: String,
+    pub attempts: u32,
+    // Why the last attempt failed.
+    pub error: Option<String>,
+    pub date_added: String,
+}
+
+// What we remember about a file to recognize it after it moves.
+pub struct FileIdentity {
+    pub beat_id: u32,
+    pub file_path: String,
+    pub file_size: Option<u64>,
+    pub fingerprint: Option<Vec<u32>>,
+}
+
+// What duplicate detection compares. Beats imported before hashes and
+// fingerprints were stored have neither until `set_content_identity` fills them in.
+pub struct DuplicateCandidate {
+    pub beat_id: u32,
+    pub file_path: String,
+    pub duration: String,
+    pub missing: bool,
+    pub content_hash: Option<String>,
+    pub fingerprint: Option<Vec<u32>>,
+}
+
 #[derive(serde::Deserialize)]
 pub struct RowOrder {
     row_id: String,
     row_number: i32,
 }
 
+// Settings keys
+pub const SETTING_WRITE_TAGS_ON_EDIT: &str = "write_tags_on_edit";
+pub const SETTING_PLAYBACK_TICK_RATE: &str = "playback_tick_rate";
+pub const SETTING_TRANSITION: &str = "transition";
+pub const SETTING_OUTPUT_DEVICE: &str = "output_device";
+pub const SETTING_PREVIEW_DEVICE: &str = "preview_device";
+pub const SETTING_NORMALIZATION: &str = "normalization";
+
+// Stored as a beat's artist when its file has no artist tag.
+const UNKNOWN_ARTIST: &str = "Unknown";
+
+// Columns selected for every `Beat`, in the order `beat_from_row` expects.
+const BEAT_COLUMNS: &str = "b.id, b.title, b.bpm, b.musical_key, b.duration, b.artist, b.date_added, b.file_path, b.row_number, b.bpm_confidence, b.camelot_key, b.album, b.genre, b.comment, b.missing, b.loudness_lufs, b.true_peak_db, b.replay_gain_db";
+
 lazy_static! {
     static ref DB_PATH: String = get_db_path();
     static ref CONNECTION: Mutex<Connection> = Mutex::new(establish_db_connection());
 }
 
-// Check if a database file exists, and create one if it does not.
-pub fn init() {
+// Check if a database file exists, and create one if it does not, then bring
+// its schema up to date.
+pub fn init() -> Result<(), MigrationError> {
     println!("Initializing database...");
     if !db_file_exists() {
         println!("Database file does not exist. Creating...");
@@ -58,102 +230,229 @@ pub fn init() {
     } else {
         println!("Database file already exists.");
     }
-    let conn = CONNECTION.lock().unwrap();
-    create_beat_table(&conn);
-    init_column_vis(&conn);
-    create_set_tables(&conn);
-}
-
-fn create_set_tables(conn: &Connection) {
-    println!("Creating set tables...\n");
-    let create_set_name_table_sql = "
-        CREATE TABLE IF NOT EXISTS set_name (
-            id INTEGER PRIMARY KEY,
-            set_name TEXT NOT NULL
-        );
-    ";
-
-    let create_set_beat_table_sql = "
-        CREATE TABLE IF NOT EXISTS set_beat (
-            set_id INTEGER,
-            beat_id INTEGER,
-            PRIMARY KEY (set_id, beat_id),
-            FOREIGN KEY (set_id) REFERENCES set_name(id),
-            FOREIGN KEY (beat_id) REFERENCES beats(id)
-        );
-    ";
-
-    match conn.execute(create_set_name_table_sql, []) {
-        Ok(_) => println!("set_name table created successfully."),
-        Err(e) => println!("Error creating set_name table: {}", e),
-    }
-
-    match conn.execute(create_set_beat_table_sql, []) {
-        Ok(_) => println!("set_beat table created successfully."),
-        Err(e) => println!("Error creating set_beat table: {}", e),
-    }
+    let mut conn = CONNECTION.lock().unwrap();
+    migrations::run(&mut conn)
 }
 
 pub fn add_beat(file_path: String) -> Result<(), Box<dyn std::error::Error>> {
     let path = Path::new(&file_path);
-    
-    // Extract title from file name
-    let title = path.file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("Unknown")
-        .to_string();
+    if find_beat_by_path(&file_path)?.is_some() {
+        return Err(format!("{} is already in the library", file_path).into());
+    }
 
     // Open the media source
     let file = File::open(path)?;
     let mss = MediaSourceStream::new(Box::new(file), Default::default());
 
     // Create a hint to help the format registry guess what format reader is appropriate
-    let hint = Hint::new();
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
 
     // Use the default options for metadata and format readers
     let meta_opts: MetadataOptions = Default::default();
     let fmt_opts: FormatOptions = Default::default();
 
     // Probe the media source
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
+    let mut probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
+
+    // Read embedded ID3v2 / Vorbis comment / RIFF INFO tags before handing off the reader
+    let tags = tags::read_tags(path, &mut probed);
 
     // Get the instantiated format reader
     let format = probed.format;
 
     // Get metadata from the format reader
     let track = format.default_track().unwrap();
-    let bpm = 0;
     let duration = track.codec_params.time_base
         .map(|tb| tb.calc_time(track.codec_params.n_frames.unwrap_or(0)))
-        .map(|time| format_time(time))
+        .map(format_time)
         .unwrap_or("0:00".to_string());
 
-    // Note: Depending on your metadata extraction needs, you may need to parse the artist and musical key differently.
-    let musical_key = "Unknown".to_string();
-    let artist = "Unknown".to_string();
+    // Fall back to the file name when there's no title tag
+    let title = tags.title.unwrap_or_else(|| {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("Unknown")
+            .to_string()
+    });
+    let artist = tags.artist.unwrap_or(UNKNOWN_ARTIST.to_string());
+    let tagged_key = tags.key.as_deref().map(|key| (key, analysis::MusicalKey::parse(key)));
+
+    // Anything that needs the audio decoded is left to a background job, so
+    // the beat goes in with its tags and a BPM of 0 or a key without a
+    // Camelot code until tempo and key detection fill them in.
+    let file_size = fs::metadata(path).ok().map(|m| m.len() as i64);
+    let content_hash = duplicates::hash_file(path)?;
+
+    let bpm = tags.bpm.map_or(0, |bpm| bpm.round() as u32);
+    let (musical_key, camelot_key) = match tagged_key {
+        Some((_, Some(key))) => (key.standard(), Some(key.camelot())),
+        // Keep an unrecognized key tag verbatim rather than throwing it away.
+        Some((raw, None)) => (raw.to_string(), None),
+        None => ("Unknown".to_string(), None),
+    };
 
     // Call commit_beat with extracted information
-    commit_beat(file_path, title, bpm, musical_key, duration, artist)?;
+    let beat_id = commit_beat(NewBeat {
+        file_path,
+        title,
+        bpm,
+        bpm_confidence: None,
+        musical_key,
+        camelot_key,
+        duration,
+        artist,
+        album: tags.album,
+        genre: tags.genre,
+        comment: tags.comment,
+        file_size,
+        fingerprint: None,
+        content_hash,
+        loudness: None,
+    })?;
+    jobs::queue_import_analysis(beat_id)?;
+
+    Ok(())
+}
+
+pub fn set_musical_key(beat_id: u32, musical_key: &str, camelot_key: Option<&str>) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("UPDATE beats SET musical_key = ?1, camelot_key = ?2 WHERE id = ?3", params![musical_key, camelot_key, beat_id])?;
+    Ok(())
+}
+
+pub fn set_loudness(beat_id: u32, loudness: &analysis::Loudness) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE beats SET loudness_lufs = ?1, true_peak_db = ?2, replay_gain_db = ?3 WHERE id = ?4",
+        params![loudness.integrated_lufs, loudness.true_peak_db, loudness.replay_gain_db, beat_id],
+    )?;
+    Ok(())
+}
 
+pub fn set_detected_bpm(beat_id: u32, bpm: u32, confidence: f64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("UPDATE beats SET bpm = ?1, bpm_confidence = ?2 WHERE id = ?3", params![bpm, confidence, beat_id])?;
     Ok(())
 }
 
+// What the analysis of a newly imported beat found. Anything left out keeps
+// its stored value.
+#[derive(Default)]
+pub struct ImportAnalysis {
+    pub tempo: Option<analysis::TempoEstimate>,
+    pub key: Option<analysis::MusicalKey>,
+    pub fingerprint: Option<Vec<u32>>,
+    pub loudness: Option<analysis::Loudness>,
+}
+
+// Save an import's analysis all at once, so none of it lands without the rest.
+pub fn save_import_analysis(beat_id: u32, found: &ImportAnalysis) -> Result<()> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    if let Some(tempo) = found.tempo {
+        tx.execute(
+            "UPDATE beats SET bpm = ?1, bpm_confidence = ?2 WHERE id = ?3",
+            params![tempo.bpm.round() as u32, tempo.confidence as f64, beat_id],
+        )?;
+    }
+    if let Some(key) = found.key {
+        tx.execute(
+            "UPDATE beats SET musical_key = ?1, camelot_key = ?2 WHERE id = ?3",
+            params![key.standard(), key.camelot(), beat_id],
+        )?;
+    }
+    if let Some(fingerprint) = &found.fingerprint {
+        tx.execute(
+            "UPDATE beats SET fingerprint = ?1 WHERE id = ?2",
+            params![analysis::encode_fingerprint(fingerprint), beat_id],
+        )?;
+    }
+    if let Some(loudness) = found.loudness {
+        tx.execute(
+            "UPDATE beats SET loudness_lufs = ?1, true_peak_db = ?2, replay_gain_db = ?3 WHERE id = ?4",
+            params![loudness.integrated_lufs, loudness.true_peak_db, loudness.replay_gain_db, beat_id],
+        )?;
+    }
+    tx.commit()
+}
+
 pub fn delete_beat(beat_id: i64) -> Result<()> {
     let mut conn = CONNECTION.lock().unwrap();
     let tx = conn.transaction()?;
+    tx.execute("DELETE FROM loop_regions WHERE beat_id = ?1", params![beat_id])?;
+    tx.execute("DELETE FROM cue_points WHERE beat_id = ?1", params![beat_id])?;
+    tx.execute("DELETE FROM waveforms WHERE beat_id = ?1", params![beat_id])?;
+    tx.execute("DELETE FROM beatgrids WHERE beat_id = ?1", params![beat_id])?;
+    tx.execute("DELETE FROM jobs WHERE beat_id = ?1", params![beat_id])?;
     tx.execute("DELETE FROM beats WHERE id = ?1", params![beat_id])?;
     tx.commit()?;
     Ok(())
 }
 
 pub fn update_beat(beat: EditThisBeat) -> Result<()> {
+    // Keep both notations in sync with whatever the user typed. Anything we
+    // can't parse is stored as-is without a Camelot code.
+    let parsed_key = analysis::MusicalKey::parse(&beat.key);
+    let musical_key = parsed_key.map(|k| k.standard()).unwrap_or(beat.key);
+    let camelot_key = parsed_key.map(|k| k.camelot());
+
     let mut conn = CONNECTION.lock().unwrap();
     let tx = conn.transaction()?;
-    tx.execute("UPDATE beats SET title = ?1, bpm = ?2, musical_key = ?3, duration = ?4, artist = ?5 WHERE id = ?6", params![beat.title, beat.bpm, beat.key, beat.duration, beat.artist, beat.id])?;
+    // A hand-edited BPM no longer comes from the detector, so drop its confidence.
+    tx.execute("UPDATE beats SET title = ?1, bpm = ?2, musical_key = ?3, duration = ?4, artist = ?5,
+                bpm_confidence = CASE WHEN bpm = ?2 THEN bpm_confidence ELSE NULL END,
+                camelot_key = ?7
+                WHERE id = ?6", params![beat.title, beat.bpm, musical_key, beat.duration, beat.artist, beat.id, camelot_key])?;
     tx.commit()?;
     Ok(())
 }
 
+// Push a beat's title, artist, BPM and key from the database into the file's
+// own tags. With `dry_run` the file is left untouched and the report only
+// lists what would change.
+pub fn write_beat_tags(beat_id: u32, dry_run: bool) -> Result<TagWriteReport, Box<dyn std::error::Error>> {
+    let beat = get_beat(beat_id)?;
+    let values = tags::TagValues {
+        title: beat.title,
+        // The placeholder for a missing artist tag isn't a real artist, so
+        // leave the file's artist alone rather than writing it in.
+        artist: if beat.artist == UNKNOWN_ARTIST { String::new() } else { beat.artist },
+        bpm: beat.bpm,
+        musical_key: beat.musical_key,
+    };
+    let changes = tags::write_tags(Path::new(&beat.file_path), &values, dry_run)?;
+    Ok(TagWriteReport { beat_id, file_path: beat.file_path, dry_run, changes })
+}
+
+pub fn get_setting(key: &str) -> Result<Option<String>> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row("SELECT value FROM settings WHERE key = ?1", params![key], |row| row.get(0))
+        .optional()
+}
+
+pub fn set_setting(key: &str, value: &str) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+pub fn delete_setting(key: &str) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("DELETE FROM settings WHERE key = ?1", params![key])?;
+    Ok(())
+}
+
+pub fn get_bool_setting(key: &str) -> Result<bool> {
+    Ok(get_setting(key)?.is_some_and(|value| value == "true"))
+}
+
 pub fn create_set(set_name: &str) -> Result<i64> {
     let conn = CONNECTION.lock().unwrap();
     conn.execute("INSERT INTO set_name (set_name) VALUES (?1)", params![set_name])?;
@@ -200,27 +499,15 @@ pub fn get_set_name(set_id: u32) -> Result<String, rusqlite::Error> {
 // TODO: Implement on frontend
 pub fn get_beats_in_set(set_id: u32) -> Result<Vec<Beat>, rusqlite::Error> {
     let conn = CONNECTION.lock().unwrap();
-    let mut stmt = conn.prepare("
-        SELECT b.id, b.title, b.bpm, b.musical_key, b.duration, b.artist, b.date_added, b.file_path, b.row_number
+    let mut stmt = conn.prepare(&format!("
+        SELECT {}
         FROM beats b
         JOIN set_beat sb ON b.id = sb.beat_id
         WHERE sb.set_id = ?1
         ORDER BY b.row_number
-    ")?;
+    ", BEAT_COLUMNS))?;
 
-    let beat_iter = stmt.query_map(params![set_id], |row| {
-        Ok(Beat {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            bpm: row.get(2)?,
-            musical_key: row.get(3)?,
-            duration: row.get(4)?,
-            artist: row.get(5)?,
-            date_added: row.get(6)?,
-            file_path: row.get(7)?,
-            row_number: row.get(8)?,
-        })
-    })?;
+    let beat_iter = stmt.query_map(params![set_id], beat_from_row)?;
 
     // Collect the results into a Vec<Beat>
     let beats: Result<Vec<Beat>, rusqlite::Error> = beat_iter.collect();
@@ -234,48 +521,6 @@ fn establish_db_connection() -> Connection {
         .unwrap_or_else(|_| panic!("Error connecting to {}", *DB_PATH))
 }
 
-fn create_beat_table(conn: &Connection) {
-    println!("Creating beats table...\n");
-    let create_table_sql = "
-        CREATE TABLE IF NOT EXISTS beats (
-            id INTEGER PRIMARY KEY,
-            title VARCHAR(255) NOT NULL,
-            bpm INTEGER
-            musical_key VARCHAR(2),
-            duration varchar(255) NOT NULL,
-            artist varchar(255),
-            date_added varchar(10) NOT NULL,
-            file_path TEXT NOT NULL,
-            row_number INTEGER NOT NULL
-        );
-    ";
-
-    match conn.execute(create_table_sql, []) {
-        Ok(_) => println!("beats table created successfully."),
-        Err(e) => println!("Error creating beats table: {}", e),
-    }
-}
-
-fn init_column_vis(conn: &Connection) {
-    println!("Creating column visibility table...\n");
-    let create_table_sql = "
-        CREATE TABLE IF NOT EXISTS column_visibility (
-            title BOOLEAN NOT NULL DEFAULT TRUE,
-            bpm BOOLEAN NOT NULL DEFAULT TRUE,
-            `key` BOOLEAN NOT NULL DEFAULT TRUE,
-            duration BOOLEAN NOT NULL DEFAULT TRUE,
-            artist BOOLEAN NOT NULL DEFAULT FALSE,
-            date_added BOOLEAN NOT NULL DEFAULT FALSE,
-            file_path BOOLEAN NOT NULL DEFAULT FALSE
-        );
-    ";
-
-    match conn.execute(create_table_sql, []) {
-        Ok(_) => println!("Column visibility table created successfully."),
-        Err(e) => println!("Error creating column visibility table: {}", e),
-    }
-}
-
 pub fn fetch_column_vis() -> Result<Vec<ColumnVisibility>> {
     println!("Fetching column visibility... \n");
     let conn = CONNECTION.lock().unwrap();
@@ -318,23 +563,537 @@ pub fn fetch_column_vis() -> Result<Vec<ColumnVisibility>> {
 //     Ok(beat)
 // }
 
-pub fn fetch_beats() -> Result<Vec<Beat>> {
-    println!("Fetching beats... \n");
+fn beat_from_row(row: &rusqlite::Row) -> Result<Beat> {
+    Ok(Beat {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        bpm: row.get(2)?,
+        musical_key: row.get(3)?,
+        duration: row.get(4)?,
+        artist: row.get(5)?,
+        date_added: row.get(6)?,
+        file_path: row.get(7)?,
+        row_number: row.get(8)?,
+        bpm_confidence: row.get(9)?,
+        camelot_key: row.get(10)?,
+        album: row.get(11)?,
+        genre: row.get(12)?,
+        comment: row.get(13)?,
+        missing: row.get(14)?,
+        loudness_lufs: row.get(15)?,
+        true_peak_db: row.get(16)?,
+        replay_gain_db: row.get(17)?,
+    })
+}
+
+pub fn get_beat(beat_id: u32) -> Result<Beat> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(&format!("SELECT {} FROM beats b WHERE b.id = ?1", BEAT_COLUMNS), params![beat_id], beat_from_row)
+}
+
+pub fn find_beat_by_path(file_path: &str) -> Result<Option<(u32, bool)>> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row("SELECT id, missing FROM beats WHERE file_path = ?1", params![file_path], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+    .optional()
+}
+
+// Point every beat at `from` (or inside the folder `from`) at `to` instead.
+// Returns the number of beats updated.
+pub fn rename_file_paths(from: &str, to: &str) -> Result<usize> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE beats SET file_path = ?2 || substr(file_path, length(?1) + 1), missing = FALSE
+         WHERE file_path = ?1 OR substr(file_path, 1, length(?1) + 1) = ?1 || ?3",
+        params![from, to, std::path::MAIN_SEPARATOR_STR],
+    )
+}
+
+// Flag (or unflag) the beat at `file_path`, or every beat inside it when it's
+// a folder. Returns the number of beats whose flag changed.
+pub fn set_missing_by_path(file_path: &str, missing: bool) -> Result<usize> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE beats SET missing = ?2
+         WHERE missing != ?2 AND (file_path = ?1 OR substr(file_path, 1, length(?1) + 1) = ?1 || ?3)",
+        params![file_path, missing, std::path::MAIN_SEPARATOR_STR],
+    )
+}
+
+// Every beat's id, path and missing flag.
+pub fn fetch_path_status() -> Result<Vec<(u32, String, bool)>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT id, file_path, missing FROM beats")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
+
+pub fn set_missing_flags(flags: &[(u32, bool)]) -> Result<()> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE beats SET missing = ?1 WHERE id = ?2")?;
+        for (beat_id, missing) in flags {
+            stmt.execute(params![missing, beat_id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn fetch_missing_identities() -> Result<Vec<FileIdentity>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT id, file_path, file_size, fingerprint FROM beats WHERE missing = TRUE")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(FileIdentity {
+            beat_id: row.get(0)?,
+            file_path: row.get(1)?,
+            file_size: row.get::<_, Option<i64>>(2)?.map(|size| size as u64),
+            fingerprint: row.get::<_, Option<Vec<u8>>>(3)?.map(|bytes| analysis::decode_fingerprint(&bytes)),
+        })
+    })?;
+    rows.collect()
+}
+
+// Point each beat at its new file and clear its missing flag, all or nothing.
+pub fn relocate_beats(moves: &[(u32, String)]) -> Result<()> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE beats SET file_path = ?1, missing = FALSE WHERE id = ?2")?;
+        for (beat_id, file_path) in moves {
+            stmt.execute(params![file_path, beat_id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn fetch_duplicate_candidates() -> Result<Vec<DuplicateCandidate>> {
     let conn = CONNECTION.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, title, bpm, musical_key, duration, artist, date_added, file_path, row_number FROM beats ORDER BY row_number")?;
-    let beat_iter = stmt.query_map([], |row| {
-        Ok(Beat {
+    let mut stmt = conn.prepare("SELECT id, file_path, duration, missing, content_hash, fingerprint FROM beats ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(DuplicateCandidate {
+            beat_id: row.get(0)?,
+            file_path: row.get(1)?,
+            duration: row.get(2)?,
+            missing: row.get(3)?,
+            content_hash: row.get(4)?,
+            fingerprint: row.get::<_, Option<Vec<u8>>>(5)?.map(|bytes| analysis::decode_fingerprint(&bytes)),
+        })
+    })?;
+    rows.collect()
+}
+
+// Store a hash computed after import. A fingerprint that couldn't be computed
+// leaves the stored one alone.
+pub fn set_content_identity(beat_id: u32, content_hash: &str, fingerprint: Option<&[u32]>) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE beats SET content_hash = ?1, fingerprint = COALESCE(?2, fingerprint) WHERE id = ?3",
+        params![content_hash, fingerprint.map(analysis::encode_fingerprint), beat_id],
+    )?;
+    Ok(())
+}
+
+// Fold `duplicate_ids` into `keep_id`: the kept beat joins every set the
+// duplicates were in and takes over their loop regions and cue points, then
+// the duplicates are removed from the library (their files stay on disk).
+// Returns the number of set memberships added.
+pub fn merge_beats(keep_id: u32, duplicate_ids: &[u32]) -> Result<usize> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    for duplicate_id in duplicate_ids {
+        added += tx.execute(
+            "INSERT OR IGNORE INTO set_beat (set_id, beat_id) SELECT set_id, ?1 FROM set_beat WHERE beat_id = ?2",
+            params![keep_id, duplicate_id],
+        )?;
+        tx.execute("DELETE FROM set_beat WHERE beat_id = ?1", params![duplicate_id])?;
+        tx.execute("UPDATE loop_regions SET beat_id = ?1 WHERE beat_id = ?2", params![keep_id, duplicate_id])?;
+        tx.execute("UPDATE cue_points SET beat_id = ?1 WHERE beat_id = ?2", params![keep_id, duplicate_id])?;
+        tx.execute("DELETE FROM waveforms WHERE beat_id = ?1", params![duplicate_id])?;
+        tx.execute("DELETE FROM beatgrids WHERE beat_id = ?1", params![duplicate_id])?;
+        tx.execute("DELETE FROM jobs WHERE beat_id = ?1", params![duplicate_id])?;
+        tx.execute("DELETE FROM beats WHERE id = ?1", params![duplicate_id])?;
+    }
+    tx.commit()?;
+    Ok(added)
+}
+
+pub fn add_loop_region(beat_id: u32, name: &str, start_seconds: f32, end_seconds: f32) -> Result<i64> {
+    let conn = CONNECTION.lock().unwrap();
+    let current_date = Local::now().format("%m/%d/%Y").to_string();
+    conn.execute(
+        "INSERT INTO loop_regions (beat_id, name, start_seconds, end_seconds, date_added) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![beat_id, name, start_seconds, end_seconds, current_date],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn loop_region_from_row(row: &rusqlite::Row) -> Result<LoopRegion> {
+    Ok(LoopRegion {
+        id: row.get(0)?,
+        beat_id: row.get(1)?,
+        name: row.get(2)?,
+        start_seconds: row.get(3)?,
+        end_seconds: row.get(4)?,
+        date_added: row.get(5)?,
+    })
+}
+
+pub fn get_loop_region(region_id: i64) -> Result<LoopRegion> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(
+        "SELECT id, beat_id, name, start_seconds, end_seconds, date_added FROM loop_regions WHERE id = ?1",
+        params![region_id],
+        loop_region_from_row,
+    )
+}
+
+pub fn get_loop_regions(beat_id: u32) -> Result<Vec<LoopRegion>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT id, beat_id, name, start_seconds, end_seconds, date_added FROM loop_regions
+         WHERE beat_id = ?1 ORDER BY start_seconds",
+    )?;
+    let regions = stmt.query_map(params![beat_id], loop_region_from_row)?;
+    regions.collect()
+}
+
+pub fn delete_loop_region(region_id: i64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("DELETE FROM loop_regions WHERE id = ?1", params![region_id])?;
+    Ok(())
+}
+
+pub fn add_cue_point(beat_id: u32, position_seconds: f32, label: &str, color: Option<&str>) -> Result<i64> {
+    let conn = CONNECTION.lock().unwrap();
+    let current_date = Local::now().format("%m/%d/%Y").to_string();
+    conn.execute(
+        "INSERT INTO cue_points (beat_id, position_seconds, label, color, date_added) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![beat_id, position_seconds, label, color, current_date],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Returns false when there's no cue with that id.
+pub fn update_cue_point(cue_id: i64, position_seconds: f32, label: &str, color: Option<&str>) -> Result<bool> {
+    let conn = CONNECTION.lock().unwrap();
+    let updated = conn.execute(
+        "UPDATE cue_points SET position_seconds = ?1, label = ?2, color = ?3 WHERE id = ?4",
+        params![position_seconds, label, color, cue_id],
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn delete_cue_point(cue_id: i64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("DELETE FROM cue_points WHERE id = ?1", params![cue_id])?;
+    Ok(())
+}
+
+fn cue_point_from_row(row: &rusqlite::Row) -> Result<CuePoint> {
+    Ok(CuePoint {
+        id: row.get(0)?,
+        beat_id: row.get(1)?,
+        position_seconds: row.get(2)?,
+        label: row.get(3)?,
+        color: row.get(4)?,
+        date_added: row.get(5)?,
+    })
+}
+
+pub fn get_cue_point(cue_id: i64) -> Result<CuePoint> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(
+        "SELECT id, beat_id, position_seconds, label, color, date_added FROM cue_points WHERE id = ?1",
+        params![cue_id],
+        cue_point_from_row,
+    )
+}
+
+pub fn get_cue_points(beat_id: u32) -> Result<Vec<CuePoint>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT id, beat_id, position_seconds, label, color, date_added FROM cue_points
+         WHERE beat_id = ?1 ORDER BY position_seconds",
+    )?;
+    let cues = stmt.query_map(params![beat_id], cue_point_from_row)?;
+    cues.collect()
+}
+
+// A cached waveform in the given layout, with what's needed to tell whether
+// the file has changed since.
+pub fn get_cached_waveform(beat_id: u32, resolution: &str, version: u32) -> Result<Option<CachedWaveform>> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(
+        "SELECT content_hash, file_size, file_modified, data FROM waveforms
+         WHERE beat_id = ?1 AND resolution = ?2 AND version = ?3",
+        params![beat_id, resolution, version],
+        |row| {
+            Ok(CachedWaveform {
+                content_hash: row.get(0)?,
+                file_size: row.get(1)?,
+                file_modified: row.get(2)?,
+                data: row.get(3)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Record that a beat's file was touched without its contents changing.
+pub fn set_waveform_file_stamp(beat_id: u32, file_size: i64, file_modified: i64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE waveforms SET file_size = ?2, file_modified = ?3 WHERE beat_id = ?1",
+        params![beat_id, file_size, file_modified],
+    )?;
+    Ok(())
+}
+
+pub fn get_beatgrid(beat_id: u32) -> Result<Option<Beatgrid>> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(
+        "SELECT beat_id, bpm, first_downbeat, duration, beats, edited FROM beatgrids WHERE beat_id = ?1",
+        params![beat_id],
+        |row| {
+            let beats: Option<Vec<u8>> = row.get(4)?;
+            Ok(Beatgrid {
+                beat_id: row.get(0)?,
+                bpm: row.get(1)?,
+                first_downbeat: row.get(2)?,
+                duration: row.get(3)?,
+                beats: beats.map(|bytes| {
+                    bytes
+                        .chunks_exact(8)
+                        .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                        .collect()
+                }),
+                edited: row.get(5)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub fn save_beatgrid(grid: &Beatgrid) -> Result<()> {
+    let beats: Option<Vec<u8>> = grid
+        .beats
+        .as_ref()
+        .map(|beats| beats.iter().flat_map(|beat| beat.to_le_bytes()).collect());
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "INSERT OR REPLACE INTO beatgrids (beat_id, bpm, first_downbeat, duration, beats, edited)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![grid.beat_id, grid.bpm, grid.first_downbeat, grid.duration, beats, grid.edited],
+    )?;
+    Ok(())
+}
+
+const JOB_COLUMNS: &str = "id, beat_id, kind, priority, status, attempts, error, date_added";
+
+fn job_from_row(row: &rusqlite::Row) -> Result<Job> {
+    Ok(Job {
+        id: row.get(0)?,
+        beat_id: row.get(1)?,
+        kind: row.get(2)?,
+        priority: row.get(3)?,
+        status: row.get(4)?,
+        attempts: row.get(5)?,
+        error: row.get(6)?,
+        date_added: row.get(7)?,
+    })
+}
+
+// Queue a job, unless the same work on the same beat is already waiting or
+// running, in which case that job is kept at the higher of the two priorities.
+pub fn enqueue_job(beat_id: u32, kind: &str, priority: i64) -> Result<Job> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    let existing: Option<i64> = tx
+        .query_row(
+            "SELECT id FROM jobs WHERE beat_id = ?1 AND kind = ?2 AND status IN ('queued', 'running')",
+            params![beat_id, kind],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let job_id = match existing {
+        Some(job_id) => {
+            tx.execute("UPDATE jobs SET priority = MAX(priority, ?1) WHERE id = ?2", params![priority, job_id])?;
+            job_id
+        }
+        None => {
+            let current_date = Local::now().format("%m/%d/%Y").to_string();
+            tx.execute(
+                "INSERT INTO jobs (beat_id, kind, priority, status, date_added) VALUES (?1, ?2, ?3, 'queued', ?4)",
+                params![beat_id, kind, priority, current_date],
+            )?;
+            tx.last_insert_rowid()
+        }
+    };
+    let job = tx.query_row(&format!("SELECT {} FROM jobs WHERE id = ?1", JOB_COLUMNS), params![job_id], job_from_row)?;
+    tx.commit()?;
+    Ok(job)
+}
+
+// Take the most urgent queued job that's due and mark it running.
+pub fn claim_next_job(now: i64) -> Result<Option<Job>> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    let job = tx
+        .query_row(
+            &format!(
+                "SELECT {} FROM jobs WHERE status = 'queued' AND run_after <= ?1 ORDER BY priority DESC, id LIMIT 1",
+                JOB_COLUMNS
+            ),
+            params![now],
+            job_from_row,
+        )
+        .optional()?;
+    let Some(mut job) = job else {
+        return Ok(None);
+    };
+    tx.execute("UPDATE jobs SET status = 'running', attempts = attempts + 1 WHERE id = ?1", params![job.id])?;
+    tx.commit()?;
+    job.status = "running".to_string();
+    job.attempts += 1;
+    Ok(Some(job))
+}
+
+pub fn get_job(job_id: i64) -> Result<Job> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.query_row(&format!("SELECT {} FROM jobs WHERE id = ?1", JOB_COLUMNS), params![job_id], job_from_row)
+}
+
+// Running jobs first, then the rest in the order they'll run.
+pub fn get_jobs() -> Result<Vec<Job>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM jobs ORDER BY status = 'running' DESC, status = 'queued' DESC, priority DESC, id",
+        JOB_COLUMNS
+    ))?;
+    let jobs = stmt.query_map([], job_from_row)?;
+    jobs.collect()
+}
+
+pub fn delete_job(job_id: i64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("DELETE FROM jobs WHERE id = ?1", params![job_id])?;
+    Ok(())
+}
+
+// Delete a job that no worker has claimed yet. Returns the number of rows
+// deleted, 0 when the job is running, failed or already gone.
+pub fn cancel_queued_job(job_id: i64) -> Result<usize> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("DELETE FROM jobs WHERE id = ?1 AND status = 'queued'", params![job_id])
+}
+
+// Put a failed job back in the queue to run again no earlier than `run_after`.
+pub fn retry_job(job_id: i64, error: &str, run_after: i64) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE jobs SET status = 'queued', error = ?2, run_after = ?3 WHERE id = ?1",
+        params![job_id, error, run_after],
+    )?;
+    Ok(())
+}
+
+pub fn fail_job(job_id: i64, error: &str) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute("UPDATE jobs SET status = 'failed', error = ?2 WHERE id = ?1", params![job_id, error])?;
+    Ok(())
+}
+
+// Move a beat's queued jobs up to at least `priority`.
+pub fn prioritize_jobs(beat_id: u32, priority: i64) -> Result<usize> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "UPDATE jobs SET priority = MAX(priority, ?2) WHERE beat_id = ?1 AND status = 'queued'",
+        params![beat_id, priority],
+    )
+}
+
+// Jobs left running when the app last closed never finished, so they go back
+// in the queue, unless they've had all their attempts: one that takes the
+// whole app down would otherwise crash it on every launch. Returns how many
+// were requeued and how many failed.
+pub fn requeue_running_jobs(max_attempts: u32) -> Result<(usize, usize)> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    let failed = tx.execute(
+        "UPDATE jobs SET status = 'failed', error = 'Interrupted on its last attempt'
+         WHERE status = 'running' AND attempts >= ?1",
+        params![max_attempts],
+    )?;
+    let requeued = tx.execute("UPDATE jobs SET status = 'queued' WHERE status = 'running'", [])?;
+    tx.commit()?;
+    Ok((requeued, failed))
+}
+
+pub fn store_waveform(beat_id: u32, resolution: &str, version: u32, waveform: &CachedWaveform) -> Result<()> {
+    let conn = CONNECTION.lock().unwrap();
+    conn.execute(
+        "INSERT OR REPLACE INTO waveforms (beat_id, resolution, content_hash, version, data, file_size, file_modified)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            beat_id,
+            resolution,
+            waveform.content_hash,
+            version,
+            waveform.data,
+            waveform.file_size,
+            waveform.file_modified
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_watched_folders() -> Result<Vec<WatchedFolder>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT id, path, date_added FROM watched_folders ORDER BY path")?;
+    let folders = stmt.query_map([], |row| {
+        Ok(WatchedFolder {
             id: row.get(0)?,
-            title: row.get(1)?,
-            bpm: row.get(2)?,
-            musical_key: row.get(3)?,
-            duration: row.get(4)?,
-            artist: row.get(5)?,
-            date_added: row.get(6)?,
-            file_path: row.get(7)?,
-            row_number: row.get(8)?,
+            path: row.get(1)?,
+            date_added: row.get(2)?,
         })
     })?;
+    folders.collect()
+}
+
+pub fn add_watched_folder(path: &str) -> Result<i64> {
+    let conn = CONNECTION.lock().unwrap();
+    let current_date = Local::now().format("%m/%d/%Y").to_string();
+    conn.execute("INSERT INTO watched_folders (path, date_added) VALUES (?1, ?2)", params![path, current_date])?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Stop tracking a folder; its beats stay in the library. Returns the folder's path.
+pub fn remove_watched_folder(folder_id: i64) -> Result<String> {
+    let mut conn = CONNECTION.lock().unwrap();
+    let tx = conn.transaction()?;
+    let path: String = tx.query_row("SELECT path FROM watched_folders WHERE id = ?1", params![folder_id], |row| row.get(0))?;
+    tx.execute("DELETE FROM watched_folders WHERE id = ?1", params![folder_id])?;
+    tx.commit()?;
+    Ok(path)
+}
+
+pub fn fetch_file_paths() -> Result<Vec<String>> {
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT file_path FROM beats")?;
+    let paths = stmt.query_map([], |row| row.get(0))?;
+    paths.collect()
+}
+
+pub fn fetch_beats() -> Result<Vec<Beat>> {
+    println!("Fetching beats... \n");
+    let conn = CONNECTION.lock().unwrap();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM beats b ORDER BY b.row_number", BEAT_COLUMNS))?;
+    let beat_iter = stmt.query_map([], beat_from_row)?;
 
     let beats: Vec<Beat> = beat_iter.filter_map(Result::ok).collect();
     Ok(beats)
@@ -358,7 +1117,7 @@ fn format_time(time: Time) -> String {
     format!("{}:{:02}", minutes, seconds)
 }
 
-pub fn commit_beat(file_path: String, title: String, bpm: u32, musical_key: String, duration: String, artist: String) -> Result<(), rusqlite::Error> {
+pub fn commit_beat(beat: NewBeat) -> Result<u32, rusqlite::Error> {
     let mut conn = CONNECTION.lock().unwrap();
     let tx = conn.transaction()?;
 
@@ -370,15 +1129,17 @@ pub fn commit_beat(file_path: String, title: String, bpm: u32, musical_key: Stri
 
     // Insert the new beat at the top (row_number = 1)
     tx.execute(
-        "INSERT INTO beats (title, bpm, musical_key, duration, artist, date_added, file_path, row_number) 
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1)",
-        params![title, bpm, musical_key, duration, artist, current_date, file_path],
+        "INSERT INTO beats (title, bpm, musical_key, duration, artist, date_added, file_path, row_number, bpm_confidence, camelot_key, album, genre, comment, file_size, fingerprint, content_hash, loudness_lufs, true_peak_db, replay_gain_db) 
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+        params![beat.title, beat.bpm, beat.musical_key, beat.duration, beat.artist, current_date, beat.file_path, beat.bpm_confidence, beat.camelot_key, beat.album, beat.genre, beat.comment, beat.file_size, beat.fingerprint, beat.content_hash,
+            beat.loudness.map(|l| l.integrated_lufs), beat.loudness.map(|l| l.true_peak_db), beat.loudness.map(|l| l.replay_gain_db)],
     )?;
+    let beat_id = tx.last_insert_rowid() as u32;
 
     // Commit the transaction
     tx.commit()?;
 
-    Ok(())
+    Ok(beat_id)
 }
 
 
@@ -0,0 +1,191 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::analysis;
+use crate::db;
+
+// Re-exports of one beat keep its length give or take encoder padding, so
+// only beats this close in duration are compared by fingerprint.
+const DURATION_TOLERANCE_SECONDS: u32 = 2;
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKind {
+    // Byte-for-byte the same file.
+    Exact,
+    // The same audio encoded differently.
+    Near,
+}
+
+#[derive(serde::Serialize)]
+pub struct DuplicateGroup {
+    kind: DuplicateKind,
+    beats: Vec<db::Beat>,
+}
+
+#[derive(serde::Serialize)]
+pub struct MergeReport {
+    kept: u32,
+    removed: Vec<u32>,
+    // Sets the kept beat was added to because a duplicate was in them.
+    sets_joined: usize,
+}
+
+// SHA-256 of the file's bytes, hex-encoded.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Group beats that are copies of the same file, or the same audio at a
+// different bitrate or format. Beats imported before hashes and fingerprints
+// were stored get them computed (and saved) along the way.
+pub fn find_duplicates() -> Result<Vec<DuplicateGroup>, String> {
+    let mut candidates = db::fetch_duplicate_candidates().map_err(|e| e.to_string())?;
+    for candidate in candidates.iter_mut().filter(|c| c.content_hash.is_none() && !c.missing) {
+        backfill_identity(candidate);
+    }
+
+    let mut groups = UnionFind::new(candidates.len());
+
+    let mut by_hash: HashMap<&str, usize> = HashMap::new();
+    for (index, candidate) in candidates.iter().enumerate() {
+        if let Some(hash) = &candidate.content_hash {
+            match by_hash.get(hash.as_str()) {
+                Some(&first) => groups.union(first, index),
+                None => {
+                    by_hash.insert(hash, index);
+                }
+            }
+        }
+    }
+
+    // Sweep through the beats in order of length, only comparing each one
+    // against those within the tolerance after it.
+    let mut by_length: Vec<(u32, usize)> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.fingerprint.is_some())
+        .filter_map(|(index, c)| parse_duration(&c.duration).map(|seconds| (seconds, index)))
+        .collect();
+    by_length.sort();
+    for (i, &(seconds, a)) in by_length.iter().enumerate() {
+        for &(other_seconds, b) in &by_length[i + 1..] {
+            if other_seconds - seconds > DURATION_TOLERANCE_SECONDS {
+                break;
+            }
+            if groups.find(a) == groups.find(b) {
+                continue;
+            }
+            let (Some(fa), Some(fb)) = (&candidates[a].fingerprint, &candidates[b].fingerprint) else { continue };
+            if analysis::fingerprint_similarity(fa, fb) >= analysis::FINGERPRINT_MATCH_THRESHOLD {
+                groups.union(a, b);
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..candidates.len() {
+        members.entry(groups.find(index)).or_default().push(index);
+    }
+    let mut member_lists: Vec<Vec<usize>> = members.into_values().filter(|m| m.len() > 1).collect();
+    member_lists.sort();
+
+    let mut duplicates = Vec::new();
+    for indices in member_lists {
+        let first_hash = &candidates[indices[0]].content_hash;
+        let kind = if first_hash.is_some() && indices.iter().all(|&i| &candidates[i].content_hash == first_hash) {
+            DuplicateKind::Exact
+        } else {
+            DuplicateKind::Near
+        };
+        let beats = indices
+            .iter()
+            .map(|&i| db::get_beat(candidates[i].beat_id))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        duplicates.push(DuplicateGroup { kind, beats });
+    }
+    println!("Found {} duplicate group(s) among {} beats", duplicates.len(), candidates.len());
+    Ok(duplicates)
+}
+
+fn backfill_identity(candidate: &mut db::DuplicateCandidate) {
+    let path = Path::new(&candidate.file_path);
+    let hash = match hash_file(path) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Could not hash {}: {}", candidate.file_path, e);
+            return;
+        }
+    };
+    if candidate.fingerprint.is_none() {
+        match analysis::fingerprint_file(path) {
+            Ok(fingerprint) => candidate.fingerprint = Some(fingerprint),
+            Err(e) => eprintln!("Could not fingerprint {}: {}", candidate.file_path, e),
+        }
+    }
+    if let Err(e) = db::set_content_identity(candidate.beat_id, &hash, candidate.fingerprint.as_deref()) {
+        eprintln!("Failed to store hash for {}: {}", candidate.file_path, e);
+    }
+    candidate.content_hash = Some(hash);
+}
+
+// "3:25" (or "1:02:03") to seconds. Durations edited by hand may not parse.
+fn parse_duration(duration: &str) -> Option<u32> {
+    duration
+        .trim()
+        .split(':')
+        .try_fold(0u32, |total, part| Some(total * 60 + part.parse::<u32>().ok()?))
+}
+
+// Keep `keep_id` and fold the other beats into it. The kept beat inherits every
+// set membership of the ones removed.
+pub fn merge_duplicates(keep_id: u32, duplicate_ids: Vec<u32>) -> Result<MergeReport, String> {
+    if duplicate_ids.contains(&keep_id) {
+        return Err("The beat to keep can't also be merged away".to_string());
+    }
+    for beat_id in std::iter::once(&keep_id).chain(&duplicate_ids) {
+        db::get_beat(*beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    }
+    let sets_joined = db::merge_beats(keep_id, &duplicate_ids).map_err(|e| e.to_string())?;
+    println!("Merged {:?} into beat {}", duplicate_ids, keep_id);
+    Ok(MergeReport { kept: keep_id, removed: duplicate_ids, sets_joined })
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        UnionFind { parent: (0..size).collect() }
+    }
+
+    fn find(&mut self, index: usize) -> usize {
+        let mut root = index;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut current = index;
+        while self.parent[current] != root {
+            let next = self.parent[current];
+            self.parent[current] = root;
+            current = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        // The lower index stays the root so groups keep import order.
+        if root_a != root_b {
+            self.parent[root_a.max(root_b)] = root_a.min(root_b);
+        }
+    }
+}
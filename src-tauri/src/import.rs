@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tauri::{AppHandle, Manager};
+use walkdir::WalkDir;
+
+use crate::db;
+
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["mp3", "wav", "flac"];
+
+const PROGRESS_EVENT: &str = "import-progress";
+const COMPLETE_EVENT: &str = "import-complete";
+
+#[derive(serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Imported,
+    Skipped,
+    Failed,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct ImportProgress {
+    folder: String,
+    file_path: String,
+    status: ImportStatus,
+    error: Option<String>,
+    // 1-based position of this file among the candidates found in the folder.
+    current: usize,
+    total: usize,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct ImportFailure {
+    file_path: String,
+    error: String,
+}
+
+#[derive(serde::Serialize, Clone, Default)]
+pub struct ImportSummary {
+    folder: String,
+    imported: Vec<String>,
+    skipped: Vec<String>,
+    failed: Vec<ImportFailure>,
+}
+
+pub fn is_supported_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| SUPPORTED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+// Every supported audio file below `folder`, in a stable order.
+pub fn find_audio_files(folder: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkDir::new(folder)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                eprintln!("Skipping unreadable entry while importing: {}", e);
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file() && is_supported_audio(entry.path()))
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+// Start importing every supported file under `folder` on a background thread.
+// Progress for each file is emitted as an `import-progress` event and the
+// totals as a single `import-complete` event once the walk is done.
+pub fn import_folder(app_handle: AppHandle, folder: String) -> Result<(), String> {
+    let root = PathBuf::from(&folder);
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", folder));
+    }
+
+    thread::spawn(move || {
+        let summary = run_import(&app_handle, &folder, &root);
+        println!(
+            "Import of {} finished: {} imported, {} skipped, {} failed",
+            folder,
+            summary.imported.len(),
+            summary.skipped.len(),
+            summary.failed.len()
+        );
+        if let Err(e) = app_handle.emit_all(COMPLETE_EVENT, summary) {
+            eprintln!("Failed to emit {} event: {}", COMPLETE_EVENT, e);
+        }
+    });
+    Ok(())
+}
+
+fn run_import(app_handle: &AppHandle, folder: &str, root: &Path) -> ImportSummary {
+    let mut summary = ImportSummary { folder: folder.to_string(), ..Default::default() };
+
+    let mut known_paths: HashSet<String> = match db::fetch_file_paths() {
+        Ok(paths) => paths.into_iter().collect(),
+        Err(e) => {
+            eprintln!("Failed to load existing beats, aborting import: {}", e);
+            return summary;
+        }
+    };
+
+    let files = find_audio_files(root);
+    let total = files.len();
+    println!("Importing {} audio files from {}", total, folder);
+
+    for (index, file) in files.into_iter().enumerate() {
+        let file_path = file.to_string_lossy().to_string();
+        let (status, error) = if known_paths.contains(&file_path) {
+            summary.skipped.push(file_path.clone());
+            (ImportStatus::Skipped, None)
+        } else {
+            match db::add_beat(file_path.clone()) {
+                Ok(()) => {
+                    known_paths.insert(file_path.clone());
+                    summary.imported.push(file_path.clone());
+                    (ImportStatus::Imported, None)
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    eprintln!("Failed to import {}: {}", file_path, error);
+                    summary.failed.push(ImportFailure { file_path: file_path.clone(), error: error.clone() });
+                    (ImportStatus::Failed, Some(error))
+                }
+            }
+        };
+
+        let progress = ImportProgress {
+            folder: folder.to_string(),
+            file_path,
+            status,
+            error,
+            current: index + 1,
+            total,
+        };
+        if let Err(e) = app_handle.emit_all(PROGRESS_EVENT, progress) {
+            eprintln!("Failed to emit {} event: {}", PROGRESS_EVENT, e);
+        }
+    }
+    summary
+}
@@ -0,0 +1,424 @@
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+use crate::analysis::{self, DecodedAudio};
+use crate::beatgrid;
+use crate::db::{self, Beat, Job};
+use crate::waveform::{self, Waveform, WaveformResolution};
+
+const PROGRESS_EVENT: &str = "job-progress";
+
+// A failed job is tried this many times in all, waiting longer before each
+// retry.
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY_SECONDS: i64 = 10;
+// Idle workers look for jobs whose retry delay has passed this often.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+static QUEUE: Lazy<JobQueue> = Lazy::new(JobQueue::default);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    // Everything a newly imported beat needs from a single decode: tempo and
+    // key where the tags didn't have them, fingerprint and loudness.
+    Import,
+    Bpm,
+    Key,
+    Loudness,
+    Waveform,
+    Beatgrid,
+}
+
+impl JobKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Import => "import",
+            JobKind::Bpm => "bpm",
+            JobKind::Key => "key",
+            JobKind::Loudness => "loudness",
+            JobKind::Waveform => "waveform",
+            JobKind::Beatgrid => "beatgrid",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "import" => Some(JobKind::Import),
+            "bpm" => Some(JobKind::Bpm),
+            "key" => Some(JobKind::Key),
+            "loudness" => Some(JobKind::Loudness),
+            "waveform" => Some(JobKind::Waveform),
+            "beatgrid" => Some(JobKind::Beatgrid),
+            _ => None,
+        }
+    }
+}
+
+// Higher runs first. Work on the beat that's playing jumps the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobPriority {
+    // Caches that are only nice to have ready, like waveforms.
+    Background = 0,
+    Normal = 1,
+    // Asked for by the user.
+    High = 2,
+    Playing = 3,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    // Gave up, after the last retry or on a failure that would only repeat.
+    Failed,
+    Cancelled,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct JobProgress {
+    job_id: i64,
+    beat_id: u32,
+    kind: String,
+    status: JobStatus,
+    // 0.0 - 1.0 through the current attempt.
+    progress: f32,
+    attempts: u32,
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct JobQueue {
+    // Bumped whenever there may be new work, so idle workers wake up.
+    generation: Mutex<u64>,
+    wake: Condvar,
+    // Cancellation flags of the jobs being worked on.
+    running: Mutex<HashMap<i64, Arc<AtomicBool>>>,
+}
+
+impl JobQueue {
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.wake.notify_all();
+    }
+
+    fn current_generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    // Sleep until there's new work, or long enough for a retry to come due.
+    fn wait(&self, seen: u64) {
+        let generation = self.generation.lock().unwrap();
+        if *generation == seen {
+            let _ = self.wake.wait_timeout(generation, IDLE_POLL_INTERVAL);
+        }
+    }
+}
+
+// Why a job didn't finish. Some failures would only happen again, like a
+// track with no tempo to detect, so they aren't retried.
+enum JobError {
+    Retry(String),
+    Permanent(String),
+}
+
+impl From<String> for JobError {
+    fn from(error: String) -> Self {
+        JobError::Retry(error)
+    }
+}
+
+impl From<rusqlite::Error> for JobError {
+    fn from(error: rusqlite::Error) -> Self {
+        JobError::Retry(error.to_string())
+    }
+}
+
+fn permanent(error: &str) -> JobError {
+    JobError::Permanent(error.to_string())
+}
+
+// Handed to a running job to report progress and notice cancellation.
+struct JobContext<'a> {
+    job: &'a Job,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobContext<'_> {
+    // Call between steps: reports how far along the job is, or stops it
+    // when it has been cancelled.
+    fn step(&self, progress: f32) -> Result<(), String> {
+        if self.cancelled.load(Ordering::Acquire) {
+            return Err("Cancelled".to_string());
+        }
+        emit(self.job, JobStatus::Running, progress);
+        Ok(())
+    }
+}
+
+fn emit(job: &Job, status: JobStatus, progress: f32) {
+    let Some(app_handle) = APP_HANDLE.get() else {
+        return;
+    };
+    let event = JobProgress {
+        job_id: job.id,
+        beat_id: job.beat_id,
+        kind: job.kind.clone(),
+        status,
+        progress,
+        attempts: job.attempts,
+        error: job.error.clone(),
+    };
+    if let Err(e) = app_handle.emit_all(PROGRESS_EVENT, event) {
+        eprintln!("Failed to emit {} event: {}", PROGRESS_EVENT, e);
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+// Start one worker per CPU on the jobs left in the queue, including any that
+// were running when the app last closed.
+pub fn start(app_handle: AppHandle) -> Result<(), String> {
+    APP_HANDLE
+        .set(app_handle)
+        .map_err(|_| "Job workers are already running".to_string())?;
+    let (requeued, failed) = db::requeue_running_jobs(MAX_ATTEMPTS).map_err(|e| e.to_string())?;
+    if requeued > 0 {
+        println!("Requeued {} interrupted jobs", requeued);
+    }
+    if failed > 0 {
+        eprintln!("Gave up on {} jobs interrupted on their last attempt", failed);
+    }
+    let workers = thread::available_parallelism().map_or(1, |count| count.get());
+    println!("Starting {} job workers", workers);
+    for index in 0..workers {
+        thread::Builder::new()
+            .name(format!("job-worker-{}", index))
+            .spawn(worker)
+            .map_err(|e| format!("Failed to start job worker: {}", e))?;
+    }
+    Ok(())
+}
+
+fn worker() {
+    loop {
+        let seen = QUEUE.current_generation();
+        match db::claim_next_job(now()) {
+            Ok(Some(job)) => run(job),
+            Ok(None) => QUEUE.wait(seen),
+            Err(e) => {
+                eprintln!("Failed to fetch the next job: {}", e);
+                QUEUE.wait(seen);
+            }
+        }
+    }
+}
+
+fn run(mut job: Job) {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    QUEUE.running.lock().unwrap().insert(job.id, cancelled.clone());
+    emit(&job, JobStatus::Running, 0.0);
+
+    let context = JobContext { job: &job, cancelled: cancelled.clone() };
+    // A panic in the analysis shouldn't take the worker down with it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| perform(&context)))
+        .unwrap_or_else(|_| Err(JobError::Retry("Analysis crashed".to_string())));
+    QUEUE.running.lock().unwrap().remove(&job.id);
+
+    let outcome = match result {
+        _ if cancelled.load(Ordering::Acquire) => db::delete_job(job.id).map(|_| (JobStatus::Cancelled, 0.0)),
+        Ok(()) => db::delete_job(job.id).map(|_| (JobStatus::Done, 1.0)),
+        Err(error) => {
+            let (error, retry) = match error {
+                JobError::Retry(error) => (error, job.attempts < MAX_ATTEMPTS),
+                JobError::Permanent(error) => (error, false),
+            };
+            eprintln!("Job {} ({} on beat {}) failed: {}", job.id, job.kind, job.beat_id, error);
+            let result = if retry {
+                let delay = RETRY_DELAY_SECONDS << (job.attempts - 1);
+                db::retry_job(job.id, &error, now() + delay).map(|_| (JobStatus::Queued, 0.0))
+            } else {
+                db::fail_job(job.id, &error).map(|_| (JobStatus::Failed, 0.0))
+            };
+            job.error = Some(error);
+            result
+        }
+    };
+    match outcome {
+        Ok((status, progress)) => emit(&job, status, progress),
+        Err(e) => eprintln!("Failed to record the outcome of job {}: {}", job.id, e),
+    }
+}
+
+// Every kind of job writes only after its last step, so one that's cancelled
+// leaves the beat as it was.
+fn perform(context: &JobContext) -> Result<(), JobError> {
+    let job = context.job;
+    let kind = JobKind::parse(&job.kind).ok_or_else(|| JobError::Permanent(format!("Unknown job kind {}", job.kind)))?;
+    let beat = db::get_beat(job.beat_id)
+        .map_err(|e| JobError::Permanent(format!("Beat {} not found: {}", job.beat_id, e)))?;
+    match kind {
+        JobKind::Import => analyze_import(context, &beat),
+        JobKind::Bpm => {
+            let audio = decode(context, &beat)?;
+            let tempo = analysis::estimate_tempo(&audio.to_mono(), audio.sample_rate)
+                .ok_or_else(|| permanent("No tempo detected"))?;
+            context.step(0.9)?;
+            db::set_detected_bpm(beat.id(), tempo.bpm.round() as u32, tempo.confidence as f64)?;
+            Ok(())
+        }
+        JobKind::Key => {
+            let audio = decode(context, &beat)?;
+            let estimate = analysis::estimate_key(&audio.to_mono(), audio.sample_rate)
+                .ok_or_else(|| permanent("No key detected"))?;
+            context.step(0.9)?;
+            db::set_musical_key(beat.id(), &estimate.key.standard(), Some(&estimate.key.camelot()))?;
+            Ok(())
+        }
+        JobKind::Loudness => {
+            let audio = decode(context, &beat)?;
+            let loudness =
+                analysis::measure_loudness(&audio).ok_or_else(|| permanent("Too short or silent to measure"))?;
+            context.step(0.9)?;
+            db::set_loudness(beat.id(), &loudness)?;
+            Ok(())
+        }
+        JobKind::Waveform => {
+            let path = Path::new(beat.file_path());
+            if waveform::cached_waveform(beat.id(), path, WaveformResolution::Overview)?.is_some() {
+                return Ok(());
+            }
+            let audio = decode(context, &beat)?;
+            let waveforms = Waveform::compute_all(&audio);
+            context.step(0.9)?;
+            waveform::store_waveforms(beat.id(), path, &waveforms)?;
+            Ok(())
+        }
+        JobKind::Beatgrid => {
+            if db::get_beatgrid(beat.id())?.is_some() {
+                return Ok(());
+            }
+            let audio = decode(context, &beat)?;
+            let grid = beatgrid::estimate(&beat, &audio).ok_or_else(|| permanent("No beats detected"))?;
+            context.step(0.9)?;
+            // The user may have opened, and edited, the grid in the meantime.
+            if db::get_beatgrid(beat.id())?.is_none() {
+                db::save_beatgrid(&grid)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn decode(context: &JobContext, beat: &Beat) -> Result<DecodedAudio, String> {
+    let audio = analysis::decode_file(Path::new(beat.file_path()))
+        .map_err(|e| format!("Failed to decode {}: {}", beat.file_path(), e))?;
+    context.step(0.5)?;
+    Ok(audio)
+}
+
+// Tagged values win over detection, so tempo and key are only estimated when
+// the import found no BPM or no parseable key. Whatever could be worked out is
+// saved together at the end.
+fn analyze_import(context: &JobContext, beat: &Beat) -> Result<(), JobError> {
+    let audio = decode(context, beat)?;
+    let mono = audio.to_mono();
+
+    let mut analysis = db::ImportAnalysis::default();
+    if beat.bpm() == 0 {
+        analysis.tempo = analysis::estimate_tempo(&mono, audio.sample_rate);
+    }
+    context.step(0.6)?;
+    if beat.camelot_key().is_none() {
+        analysis.key = analysis::estimate_key(&mono, audio.sample_rate).map(|estimate| estimate.key);
+    }
+    context.step(0.7)?;
+    // Audio too short to fingerprint gives an empty one; store none instead.
+    let fingerprint = analysis::fingerprint(&mono, audio.sample_rate);
+    analysis.fingerprint = (!fingerprint.is_empty()).then_some(fingerprint);
+    context.step(0.8)?;
+    analysis.loudness = analysis::measure_loudness(&audio);
+    context.step(0.9)?;
+    db::save_import_analysis(beat.id(), &analysis)?;
+    Ok(())
+}
+
+pub fn enqueue(beat_id: u32, kind: JobKind, priority: JobPriority) -> Result<Job, String> {
+    let job = db::enqueue_job(beat_id, kind.as_str(), priority as i64).map_err(|e| e.to_string())?;
+    emit(&job, JobStatus::Queued, 0.0);
+    QUEUE.notify();
+    Ok(job)
+}
+
+// What a newly imported beat gets: its analysis first, then the caches the
+// player will want.
+pub fn queue_import_analysis(beat_id: u32) -> Result<(), String> {
+    enqueue(beat_id, JobKind::Import, JobPriority::Normal)?;
+    enqueue(beat_id, JobKind::Waveform, JobPriority::Background)?;
+    enqueue(beat_id, JobKind::Beatgrid, JobPriority::Background)?;
+    Ok(())
+}
+
+// Queue the given kinds of analysis for the given beats, or for every beat
+// whose file is there when no ids are passed.
+pub fn queue_analysis(beat_ids: Option<Vec<u32>>, kinds: &[JobKind]) -> Result<Vec<Job>, String> {
+    let beat_ids = match beat_ids {
+        Some(beat_ids) => beat_ids,
+        None => db::fetch_path_status()
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .filter(|(_, _, missing)| !missing)
+            .map(|(beat_id, _, _)| beat_id)
+            .collect(),
+    };
+    let mut jobs = Vec::new();
+    for beat_id in beat_ids {
+        for &kind in kinds {
+            jobs.push(enqueue(beat_id, kind, JobPriority::High)?);
+        }
+    }
+    Ok(jobs)
+}
+
+// Run the playing beat's outstanding work next.
+pub fn prioritize(beat_id: u32) {
+    match db::prioritize_jobs(beat_id, JobPriority::Playing as i64) {
+        Ok(0) => {}
+        Ok(_) => QUEUE.notify(),
+        Err(e) => eprintln!("Failed to prioritize jobs for beat {}: {}", beat_id, e),
+    }
+}
+
+// Drop a queued job, or stop a running one at its next step, before it has
+// written anything, so the beat keeps what it had. Failed jobs can't be
+// cancelled.
+pub fn cancel_job(job_id: i64) -> Result<(), String> {
+    let job = db::get_job(job_id).map_err(|e| format!("Job {} not found: {}", job_id, e))?;
+    // Only a job still waiting is deleted here. One a worker claimed in the
+    // meantime is stopped through its flag instead.
+    if db::cancel_queued_job(job_id).map_err(|e| e.to_string())? > 0 {
+        emit(&job, JobStatus::Cancelled, 0.0);
+        return Ok(());
+    }
+    if let Some(cancelled) = QUEUE.running.lock().unwrap().get(&job_id) {
+        cancelled.store(true, Ordering::Release);
+        return Ok(());
+    }
+    Err(format!("Job {} is not queued or running", job_id))
+}
+
+pub fn get_jobs() -> Result<Vec<Job>, String> {
+    db::get_jobs().map_err(|e| e.to_string())
+}
@@ -3,6 +3,22 @@
 
 mod db;
 mod audio;
+mod migrations;
+mod analysis;
+mod tags;
+mod import;
+mod watch;
+mod relocate;
+mod duplicates;
+mod transition;
+mod track;
+mod cues;
+mod stretch;
+mod normalization;
+mod waveform;
+mod beatgrid;
+mod jobs;
+mod spectrogram;
 
 #[derive(serde::Deserialize)]
 struct EditThisBeat {
@@ -39,6 +55,171 @@ async fn add_beat(file_path: String) -> Result<(), String> {
     db::add_beat(file_path).map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+async fn import_folder(app_handle: tauri::AppHandle, folder_path: String) -> Result<(), String> {
+    println!("importing folder: {}", folder_path);
+    import::import_folder(app_handle, folder_path)
+}
+
+#[tauri::command]
+async fn get_watched_folders() -> Result<String, String> {
+    db::get_watched_folders()
+        .map(|folders| serde_json::to_string(&folders).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn add_watched_folder(app_handle: tauri::AppHandle, folder_path: String) -> Result<i64, String> {
+    println!("watching folder: {}", folder_path);
+    watch::add_folder(app_handle, folder_path)
+}
+
+#[tauri::command]
+async fn remove_watched_folder(folder_id: i64) -> Result<(), String> {
+    watch::remove_folder(folder_id)
+}
+
+#[tauri::command]
+async fn scan_library() -> Result<String, String> {
+    relocate::scan_library().map(|report| serde_json::to_string(&report).unwrap())
+}
+
+#[tauri::command]
+async fn relocate_missing(new_root: String, dry_run: bool) -> Result<String, String> {
+    println!("relocating missing beats under: {}", new_root);
+    relocate::relocate_missing(&new_root, dry_run).map(|report| serde_json::to_string(&report).unwrap())
+}
+
+#[tauri::command]
+async fn find_duplicates() -> Result<String, String> {
+    duplicates::find_duplicates().map(|groups| serde_json::to_string(&groups).unwrap())
+}
+
+#[tauri::command]
+async fn merge_duplicates(keep_id: u32, duplicate_ids: Vec<u32>) -> Result<String, String> {
+    duplicates::merge_duplicates(keep_id, duplicate_ids).map(|report| serde_json::to_string(&report).unwrap())
+}
+
+// Queue the analysis again for the given beats, or for the whole library when
+// no ids are passed. Returns the queued jobs; results come in as job-progress
+// events.
+#[tauri::command]
+async fn reanalyze_bpm(beat_ids: Option<Vec<u32>>) -> Result<String, String> {
+    jobs::queue_analysis(beat_ids, &[jobs::JobKind::Bpm]).map(|jobs| serde_json::to_string(&jobs).unwrap())
+}
+
+#[tauri::command]
+async fn reanalyze_key(beat_ids: Option<Vec<u32>>) -> Result<String, String> {
+    jobs::queue_analysis(beat_ids, &[jobs::JobKind::Key]).map(|jobs| serde_json::to_string(&jobs).unwrap())
+}
+
+#[tauri::command]
+async fn reanalyze_loudness(beat_ids: Option<Vec<u32>>) -> Result<String, String> {
+    jobs::queue_analysis(beat_ids, &[jobs::JobKind::Loudness]).map(|jobs| serde_json::to_string(&jobs).unwrap())
+}
+
+#[tauri::command]
+async fn get_jobs() -> Result<String, String> {
+    jobs::get_jobs().map(|jobs| serde_json::to_string(&jobs).unwrap())
+}
+
+#[tauri::command]
+async fn queue_analysis(beat_ids: Option<Vec<u32>>, kinds: Vec<jobs::JobKind>) -> Result<String, String> {
+    jobs::queue_analysis(beat_ids, &kinds).map(|jobs| serde_json::to_string(&jobs).unwrap())
+}
+
+#[tauri::command]
+async fn cancel_job(job_id: i64) -> Result<(), String> {
+    jobs::cancel_job(job_id)
+}
+
+#[tauri::command]
+async fn get_waveform(beat_id: u32, resolution: waveform::WaveformResolution) -> Result<String, String> {
+    waveform::get_waveform(beat_id, resolution).map(|waveform| serde_json::to_string(&waveform).unwrap())
+}
+
+#[tauri::command]
+async fn render_spectrogram(
+    beat_id: u32,
+    width: u32,
+    height: u32,
+    fft_size: usize,
+    format: Option<spectrogram::SpectrogramFormat>,
+) -> Result<String, String> {
+    spectrogram::render_spectrogram(beat_id, width, height, fft_size, format.unwrap_or_default())
+        .map(|spectrogram| serde_json::to_string(&spectrogram).unwrap())
+}
+
+#[tauri::command]
+async fn get_beatgrid(beat_id: u32) -> Result<String, String> {
+    beatgrid::get_beatgrid(beat_id).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn reanalyze_beatgrid(beat_id: u32) -> Result<String, String> {
+    beatgrid::reanalyze_beatgrid(beat_id).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn nudge_beatgrid(beat_id: u32, seconds: f64) -> Result<String, String> {
+    beatgrid::nudge_beatgrid(beat_id, seconds).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn halve_beatgrid(beat_id: u32) -> Result<String, String> {
+    beatgrid::halve_beatgrid(beat_id).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn double_beatgrid(beat_id: u32) -> Result<String, String> {
+    beatgrid::double_beatgrid(beat_id).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn set_beatgrid_anchor(beat_id: u32, position: f64) -> Result<String, String> {
+    beatgrid::set_beatgrid_anchor(beat_id, position).map(|grid| serde_json::to_string(&grid).unwrap())
+}
+
+#[tauri::command]
+async fn play_set(set_id: u32, start_beat_id: Option<u32>) -> Result<(), String> {
+    audio::play_set(set_id, start_beat_id)
+}
+
+#[tauri::command]
+async fn enqueue_beats(beat_ids: Vec<u32>) -> Result<(), String> {
+    audio::enqueue(beat_ids)
+}
+
+#[tauri::command]
+async fn play_next(beat_ids: Vec<u32>) -> Result<(), String> {
+    audio::play_next(beat_ids)
+}
+
+#[tauri::command]
+async fn skip_track() -> Result<(), String> {
+    audio::skip()
+}
+
+#[tauri::command]
+async fn previous_track() -> Result<(), String> {
+    audio::previous()
+}
+
+#[tauri::command]
+async fn clear_queue() -> Result<(), String> {
+    audio::clear_queue()
+}
+
+#[tauri::command]
+async fn move_in_queue(from: usize, to: usize) -> Result<(), String> {
+    audio::move_in_queue(from, to)
+}
+
+#[tauri::command]
+async fn get_queue() -> Result<String, String> {
+    audio::get_queue().map(|queue| serde_json::to_string(&queue).unwrap())
+}
+
 #[tauri::command]
 async fn pause_beat() -> Result<(), String> {
     audio::pause()
@@ -59,6 +240,199 @@ async fn set_volume(volume: f32) -> Result<(), String> {
     audio::set_volume(volume)
 }
 
+#[tauri::command]
+async fn get_playback_tick_rate() -> Result<f32, String> {
+    db::get_setting(db::SETTING_PLAYBACK_TICK_RATE)
+        .map(|value| {
+            value
+                .and_then(|v| v.parse().ok())
+                .filter(|rate: &f32| rate.is_finite())
+                .unwrap_or(audio::DEFAULT_TICK_RATE)
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_playback_tick_rate(rate: f32) -> Result<(), String> {
+    audio::set_tick_rate(rate)?;
+    db::set_setting(db::SETTING_PLAYBACK_TICK_RATE, &rate.to_string()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_transition() -> Result<String, String> {
+    let transition: transition::Transition = db::get_setting(db::SETTING_TRANSITION)
+        .map_err(|e| e.to_string())?
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default();
+    Ok(serde_json::to_string(&transition).unwrap())
+}
+
+#[tauri::command]
+async fn set_transition(transition: transition::Transition) -> Result<(), String> {
+    db::set_setting(db::SETTING_TRANSITION, &serde_json::to_string(&transition).unwrap())
+        .map_err(|e| e.to_string())?;
+    audio::set_transition(transition)
+}
+
+#[tauri::command]
+async fn get_normalization() -> Result<String, String> {
+    let normalization: normalization::Normalization = db::get_setting(db::SETTING_NORMALIZATION)
+        .map_err(|e| e.to_string())?
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default();
+    Ok(serde_json::to_string(&normalization).unwrap())
+}
+
+#[tauri::command]
+async fn set_normalization(normalization: normalization::Normalization) -> Result<(), String> {
+    normalization.check()?;
+    db::set_setting(db::SETTING_NORMALIZATION, &serde_json::to_string(&normalization).unwrap())
+        .map_err(|e| e.to_string())?;
+    audio::set_normalization(normalization)
+}
+
+#[tauri::command]
+async fn set_loop(start: f32, end: f32, unit: audio::LoopUnit) -> Result<String, String> {
+    audio::set_loop(start, end, unit).map(|points| serde_json::to_string(&points).unwrap())
+}
+
+#[tauri::command]
+async fn toggle_loop() -> Result<bool, String> {
+    audio::toggle_loop()
+}
+
+#[tauri::command]
+async fn clear_loop() -> Result<(), String> {
+    audio::clear_loop()
+}
+
+#[tauri::command]
+async fn save_loop_region(beat_id: u32, name: String, start: f32, end: f32, unit: audio::LoopUnit) -> Result<i64, String> {
+    audio::save_loop_region(beat_id, &name, start, end, unit)
+}
+
+#[tauri::command]
+async fn get_loop_regions(beat_id: u32) -> Result<String, String> {
+    db::get_loop_regions(beat_id)
+        .map(|regions| serde_json::to_string(&regions).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_loop_region(region_id: i64) -> Result<(), String> {
+    db::delete_loop_region(region_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn activate_loop_region(region_id: i64) -> Result<String, String> {
+    audio::activate_loop_region(region_id).map(|points| serde_json::to_string(&points).unwrap())
+}
+
+#[tauri::command]
+async fn add_cue_point(beat_id: u32, position: f32, label: String, color: Option<String>) -> Result<i64, String> {
+    cues::add_cue_point(beat_id, position, label, color)
+}
+
+#[tauri::command]
+async fn update_cue_point(cue_id: i64, position: f32, label: String, color: Option<String>) -> Result<(), String> {
+    cues::update_cue_point(cue_id, position, label, color)
+}
+
+#[tauri::command]
+async fn delete_cue_point(cue_id: i64) -> Result<(), String> {
+    db::delete_cue_point(cue_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_cue_points(beat_id: u32) -> Result<String, String> {
+    db::get_cue_points(beat_id)
+        .map(|cues| serde_json::to_string(&cues).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn jump_to_cue(cue_id: i64) -> Result<f32, String> {
+    audio::jump_to_cue(cue_id)
+}
+
+#[tauri::command]
+async fn set_playback_rate(rate: f32) -> Result<(), String> {
+    audio::set_playback_rate(rate)
+}
+
+#[tauri::command]
+async fn set_speed_mode(mode: stretch::SpeedMode) -> Result<(), String> {
+    audio::set_speed_mode(mode)
+}
+
+#[tauri::command]
+async fn set_pitch_shift(semitones: f32) -> Result<(), String> {
+    audio::set_pitch_shift(semitones)
+}
+
+#[tauri::command]
+async fn get_output_devices() -> Result<String, String> {
+    audio::output_devices().map(|devices| serde_json::to_string(&devices).unwrap())
+}
+
+// None goes back to following the system default device.
+#[tauri::command]
+async fn set_output_device(name: Option<String>) -> Result<String, String> {
+    let device = audio::set_output_device(name.clone())?;
+    match name {
+        Some(name) => db::set_setting(db::SETTING_OUTPUT_DEVICE, &name),
+        None => db::delete_setting(db::SETTING_OUTPUT_DEVICE),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(device)
+}
+
+#[tauri::command]
+async fn preview_beat(beat_id: u32) -> Result<(), String> {
+    audio::preview_beat(beat_id)
+}
+
+#[tauri::command]
+async fn pause_preview() -> Result<(), String> {
+    audio::pause_preview()
+}
+
+#[tauri::command]
+async fn resume_preview() -> Result<(), String> {
+    audio::resume_preview()
+}
+
+#[tauri::command]
+async fn stop_preview() -> Result<(), String> {
+    audio::stop_preview()
+}
+
+#[tauri::command]
+async fn seek_preview(seconds: f32) -> Result<f32, String> {
+    audio::seek_preview(seconds).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_preview_volume(volume: f32) -> Result<(), String> {
+    audio::set_preview_volume(volume)
+}
+
+#[tauri::command]
+async fn get_preview_state() -> Result<String, String> {
+    audio::get_preview_state().map(|state| serde_json::to_string(&state).unwrap())
+}
+
+#[tauri::command]
+async fn set_preview_device(name: Option<String>) -> Result<String, String> {
+    let device = audio::set_preview_device(name.clone())?;
+    match name {
+        Some(name) => db::set_setting(db::SETTING_PREVIEW_DEVICE, &name),
+        None => db::delete_setting(db::SETTING_PREVIEW_DEVICE),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(device)
+}
+
 #[tauri::command]
 async fn add_set(name: String) -> Result<i64, String> {
     println!("Adding set: {}", name);
@@ -114,8 +488,8 @@ async fn get_beat_set(set_id: u32) -> Result<String, ()> {
 }
 
 #[tauri::command]
-async fn seek_audio(seconds: f32) -> Result<(), String> {
-    audio::seek(seconds)
+async fn seek_audio(seconds: f32) -> Result<f32, String> {
+    audio::seek(seconds).map_err(|e| e.to_string())
 }
 #[tauri::command]
 async fn save_row_order(row_order: Vec<db::RowOrder>) -> Result<(), String> {
@@ -137,32 +511,139 @@ async fn delete_beat(beat_id: i64) -> Result<(), String> {
 #[tauri::command]
 async fn restart_beat() -> Result<(), String> {
   println!("restarting beat");
-  audio::seek(0.0).map_err(|e| e.to_string())
+  audio::seek(0.0).map(|_| ()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 async fn update_beat(beat: EditThisBeat) -> Result<(), String> {
-    db::update_beat(beat).map_err(|e| e.to_string())
+    let beat_id = beat.id as u32;
+    db::update_beat(beat).map_err(|e| e.to_string())?;
+
+    // Opt-in: keep the file's own tags in step with the edit.
+    if db::get_bool_setting(db::SETTING_WRITE_TAGS_ON_EDIT).map_err(|e| e.to_string())? {
+        db::write_beat_tags(beat_id, false)
+            .map_err(|e| format!("Beat saved, but writing tags to the file failed: {}", e))?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn write_beat_tags(beat_id: u32, dry_run: bool) -> Result<String, String> {
+    db::write_beat_tags(beat_id, dry_run)
+        .map(|report| serde_json::to_string(&report).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_tag_write_back() -> Result<bool, String> {
+    db::get_bool_setting(db::SETTING_WRITE_TAGS_ON_EDIT).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_tag_write_back(enabled: bool) -> Result<(), String> {
+    db::set_setting(db::SETTING_WRITE_TAGS_ON_EDIT, if enabled { "true" } else { "false" })
+        .map_err(|e| e.to_string())
 }
 
 fn main() {
     tauri::Builder::default()
-        .setup(|_app| {
-            // Initialize the database.
-            db::init();
-            // The audio thread is automatically started when the first message is sent
+        .setup(|app| {
+            // Initialize the database and run any pending migrations.
+            db::init()?;
+            // Work through the analysis queue in the background.
+            jobs::start(app.handle())?;
+            // Keep the library in sync with watched folders. Not being able to
+            // watch shouldn't stop the app from starting.
+            if let Err(e) = watch::start(app.handle()) {
+                eprintln!("Failed to start folder watcher: {}", e);
+            }
+            // Starts the audio thread and points its playback events at the frontend.
+            // A stored rate that isn't a number (say "NaN") falls back to the default.
+            let tick_rate = db::get_setting(db::SETTING_PLAYBACK_TICK_RATE)?
+                .and_then(|value| value.parse().ok())
+                .filter(|rate: &f32| rate.is_finite())
+                .unwrap_or(audio::DEFAULT_TICK_RATE);
+            let transition = db::get_setting(db::SETTING_TRANSITION)?
+                .and_then(|value| serde_json::from_str(&value).ok())
+                .unwrap_or_default();
+            let normalization = db::get_setting(db::SETTING_NORMALIZATION)?
+                .and_then(|value| serde_json::from_str::<normalization::Normalization>(&value).ok())
+                .filter(|normalization| normalization.check().is_ok())
+                .unwrap_or_default();
+            audio::init(app.handle(), tick_rate, transition, normalization)?;
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             fetch_beats,
             fetch_column_vis,
             play_beat,
+            play_set,
+            enqueue_beats,
+            play_next,
+            skip_track,
+            previous_track,
+            clear_queue,
+            move_in_queue,
+            get_queue,
             add_beat,
+            import_folder,
+            get_watched_folders,
+            add_watched_folder,
+            remove_watched_folder,
+            scan_library,
+            relocate_missing,
+            find_duplicates,
+            merge_duplicates,
+            reanalyze_bpm,
+            reanalyze_key,
+            reanalyze_loudness,
+            get_jobs,
+            queue_analysis,
+            cancel_job,
+            get_waveform,
+            render_spectrogram,
+            get_beatgrid,
+            reanalyze_beatgrid,
+            nudge_beatgrid,
+            halve_beatgrid,
+            double_beatgrid,
+            set_beatgrid_anchor,
             pause_beat,
             resume_beat,
             stop_beat,
             set_volume,
             get_playback_state,
+            get_playback_tick_rate,
+            set_playback_tick_rate,
+            get_transition,
+            set_transition,
+            get_normalization,
+            set_normalization,
+            set_loop,
+            toggle_loop,
+            clear_loop,
+            save_loop_region,
+            get_loop_regions,
+            delete_loop_region,
+            activate_loop_region,
+            add_cue_point,
+            update_cue_point,
+            delete_cue_point,
+            get_cue_points,
+            jump_to_cue,
+            set_playback_rate,
+            set_speed_mode,
+            set_pitch_shift,
+            get_output_devices,
+            set_output_device,
+            preview_beat,
+            pause_preview,
+            resume_preview,
+            stop_preview,
+            seek_preview,
+            set_preview_volume,
+            get_preview_state,
+            set_preview_device,
             seek_audio,
             save_row_order,
             add_set,
@@ -174,7 +655,10 @@ fn main() {
             add_to_set,
             delete_beat,
             restart_beat,
-            update_beat
+            update_beat,
+            write_beat_tags,
+            get_tag_write_back,
+            set_tag_write_back
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
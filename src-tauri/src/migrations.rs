@@ -0,0 +1,390 @@
+use rusqlite::{Connection, Transaction};
+use std::fmt;
+
+// A single schema upgrade step. Migrations are applied in order and the index
+// of the last applied migration (1-based) is stored in `PRAGMA user_version`.
+struct Migration {
+    description: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+// Never edit or reorder a migration once it has shipped, only append new ones.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create base tables",
+        up: create_base_tables,
+    },
+    Migration {
+        description: "repair beats table",
+        up: repair_beats_table,
+    },
+    Migration {
+        description: "add bpm confidence",
+        up: add_bpm_confidence,
+    },
+    Migration {
+        description: "add camelot key",
+        up: add_camelot_key,
+    },
+    Migration {
+        description: "add tag columns",
+        up: add_tag_columns,
+    },
+    Migration {
+        description: "create settings table",
+        up: create_settings_table,
+    },
+    Migration {
+        description: "add watched folders and missing flag",
+        up: add_watched_folders,
+    },
+    Migration {
+        description: "add file identity columns",
+        up: add_file_identity,
+    },
+    Migration {
+        description: "add content hash",
+        up: add_content_hash,
+    },
+    Migration {
+        description: "create loop regions",
+        up: create_loop_regions,
+    },
+    Migration {
+        description: "create cue points",
+        up: create_cue_points,
+    },
+    Migration {
+        description: "add loudness columns",
+        up: add_loudness_columns,
+    },
+    Migration {
+        description: "create waveforms",
+        up: create_waveforms,
+    },
+    Migration {
+        description: "create beatgrids",
+        up: create_beatgrids,
+    },
+    Migration {
+        description: "create jobs",
+        up: create_jobs,
+    },
+    Migration {
+        description: "add waveform file stamps",
+        up: add_waveform_file_stamps,
+    },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // The database was written by a newer version of beatbank.
+    TooNew { found: i64, supported: i64 },
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::TooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the latest supported version {}",
+                found, supported
+            ),
+            MigrationError::Sqlite(e) => write!(f, "migration failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+// Bring the database up to the latest schema version. Each migration runs in
+// its own transaction together with the user_version bump, so a failure leaves
+// the database at the last successfully applied version.
+pub fn run(conn: &mut Connection) -> Result<(), MigrationError> {
+    let current = schema_version(conn)?;
+    let latest = MIGRATIONS.len() as i64;
+    if current > latest {
+        return Err(MigrationError::TooNew { found: current, supported: latest });
+    }
+    if current == latest {
+        println!("Database schema is up to date (version {}).", current);
+        return Ok(());
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index as i64 + 1;
+        println!("Running migration {}: {}...", version, migration.description);
+        let tx = conn.transaction()?;
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+    println!("Database schema upgraded from version {} to {}.", current, latest);
+    Ok(())
+}
+
+fn column_exists(tx: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = tx.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Databases created before versioning already contain these tables, which is
+// why this first step keeps the IF NOT EXISTS guards.
+fn create_base_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS beats (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR(255) NOT NULL,
+            bpm INTEGER,
+            musical_key VARCHAR(2),
+            duration varchar(255) NOT NULL,
+            artist varchar(255),
+            date_added varchar(10) NOT NULL,
+            file_path TEXT NOT NULL,
+            row_number INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS column_visibility (
+            title BOOLEAN NOT NULL DEFAULT TRUE,
+            bpm BOOLEAN NOT NULL DEFAULT TRUE,
+            `key` BOOLEAN NOT NULL DEFAULT TRUE,
+            duration BOOLEAN NOT NULL DEFAULT TRUE,
+            artist BOOLEAN NOT NULL DEFAULT FALSE,
+            date_added BOOLEAN NOT NULL DEFAULT FALSE,
+            file_path BOOLEAN NOT NULL DEFAULT FALSE
+        );
+
+        CREATE TABLE IF NOT EXISTS set_name (
+            id INTEGER PRIMARY KEY,
+            set_name TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS set_beat (
+            set_id INTEGER,
+            beat_id INTEGER,
+            PRIMARY KEY (set_id, beat_id),
+            FOREIGN KEY (set_id) REFERENCES set_name(id),
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+        ",
+    )
+}
+
+// Older builds created `beats` with a missing comma after `bpm INTEGER`, which
+// SQLite parses as a single `bpm` column with a strange type name and no
+// `musical_key` column at all. Rebuild the table with the correct definition,
+// keeping any rows that made it in.
+fn repair_beats_table(tx: &Transaction) -> rusqlite::Result<()> {
+    if column_exists(tx, "beats", "musical_key")? {
+        return Ok(());
+    }
+    println!("beats table is missing the musical_key column, rebuilding...");
+    tx.execute_batch(
+        "
+        CREATE TABLE beats_repaired (
+            id INTEGER PRIMARY KEY,
+            title VARCHAR(255) NOT NULL,
+            bpm INTEGER,
+            musical_key VARCHAR(2),
+            duration varchar(255) NOT NULL,
+            artist varchar(255),
+            date_added varchar(10) NOT NULL,
+            file_path TEXT NOT NULL,
+            row_number INTEGER NOT NULL
+        );
+
+        INSERT INTO beats_repaired (id, title, bpm, musical_key, duration, artist, date_added, file_path, row_number)
+        SELECT id, title, bpm, 'Unknown', duration, artist, date_added, file_path, row_number FROM beats;
+
+        DROP TABLE beats;
+        ALTER TABLE beats_repaired RENAME TO beats;
+        ",
+    )
+}
+
+fn add_bpm_confidence(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE beats ADD COLUMN bpm_confidence REAL;")
+}
+
+fn add_camelot_key(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE beats ADD COLUMN camelot_key TEXT;")
+}
+
+fn add_tag_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE beats ADD COLUMN album TEXT;
+        ALTER TABLE beats ADD COLUMN genre TEXT;
+        ALTER TABLE beats ADD COLUMN comment TEXT;
+        ",
+    )
+}
+
+fn create_settings_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        ",
+    )
+}
+
+fn add_watched_folders(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE watched_folders (
+            id INTEGER PRIMARY KEY,
+            path TEXT NOT NULL UNIQUE,
+            date_added varchar(10) NOT NULL
+        );
+
+        ALTER TABLE beats ADD COLUMN missing BOOLEAN NOT NULL DEFAULT FALSE;
+        ",
+    )
+}
+
+fn add_file_identity(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE beats ADD COLUMN file_size INTEGER;
+        ALTER TABLE beats ADD COLUMN fingerprint BLOB;
+        ",
+    )
+}
+
+fn add_content_hash(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE beats ADD COLUMN content_hash TEXT;
+        CREATE INDEX beats_content_hash ON beats (content_hash);
+        ",
+    )
+}
+
+fn create_loop_regions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE loop_regions (
+            id INTEGER PRIMARY KEY,
+            beat_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            start_seconds REAL NOT NULL,
+            end_seconds REAL NOT NULL,
+            date_added varchar(10) NOT NULL,
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+
+        CREATE INDEX loop_regions_beat_id ON loop_regions (beat_id);
+        ",
+    )
+}
+
+fn create_cue_points(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE cue_points (
+            id INTEGER PRIMARY KEY,
+            beat_id INTEGER NOT NULL,
+            position_seconds REAL NOT NULL,
+            label TEXT NOT NULL,
+            color TEXT,
+            date_added varchar(10) NOT NULL,
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+
+        CREATE INDEX cue_points_beat_id ON cue_points (beat_id);
+        ",
+    )
+}
+
+fn add_loudness_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE beats ADD COLUMN loudness_lufs REAL;
+        ALTER TABLE beats ADD COLUMN true_peak_db REAL;
+        ALTER TABLE beats ADD COLUMN replay_gain_db REAL;
+        ",
+    )
+}
+
+fn create_waveforms(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE waveforms (
+            beat_id INTEGER NOT NULL,
+            resolution TEXT NOT NULL,
+            content_hash TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (beat_id, resolution),
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+        ",
+    )
+}
+
+fn create_beatgrids(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE beatgrids (
+            beat_id INTEGER PRIMARY KEY,
+            bpm REAL NOT NULL,
+            first_downbeat REAL NOT NULL,
+            duration REAL NOT NULL,
+            beats BLOB,
+            edited BOOLEAN NOT NULL DEFAULT FALSE,
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+        ",
+    )
+}
+
+fn create_jobs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE jobs (
+            id INTEGER PRIMARY KEY,
+            beat_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            priority INTEGER NOT NULL,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            error TEXT,
+            run_after INTEGER NOT NULL DEFAULT 0,
+            date_added varchar(10) NOT NULL,
+            FOREIGN KEY (beat_id) REFERENCES beats(id)
+        );
+
+        CREATE INDEX jobs_status_priority ON jobs (status, priority);
+        ",
+    )
+}
+
+fn add_waveform_file_stamps(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE waveforms ADD COLUMN file_size INTEGER;
+        ALTER TABLE waveforms ADD COLUMN file_modified INTEGER;
+        ",
+    )
+}
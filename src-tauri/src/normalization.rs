@@ -0,0 +1,41 @@
+// Normalized tracks are never turned up past this true peak, so quiet tracks
+// with loud transients don't clip.
+const MAX_TRUE_PEAK_DB: f64 = -1.0;
+// Targets outside this range are almost certainly a mistake.
+pub const MIN_TARGET_LUFS: f32 = -40.0;
+pub const MAX_TARGET_LUFS: f32 = 0.0;
+
+// Whether tracks are played back at their own loudness or evened out.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    Off,
+    // Each track is turned up or down so its integrated loudness lands on
+    // the target, e.g. -14 LUFS for streaming levels or -18 for ReplayGain.
+    Loudness { target_lufs: f32 },
+}
+
+impl Normalization {
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            Normalization::Loudness { target_lufs } if !(MIN_TARGET_LUFS..=MAX_TARGET_LUFS).contains(&target_lufs) => Err(
+                format!("Target loudness must be between {} and {} LUFS", MIN_TARGET_LUFS, MAX_TARGET_LUFS),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // Linear gain for a track measured at `loudness_lufs` with the given true
+    // peak. Tracks that haven't been measured play as they are.
+    pub fn gain(&self, loudness_lufs: Option<f64>, true_peak_db: Option<f64>) -> f32 {
+        let (Normalization::Loudness { target_lufs }, Some(loudness_lufs)) = (*self, loudness_lufs) else {
+            return 1.0;
+        };
+        let mut gain_db = target_lufs as f64 - loudness_lufs;
+        if let Some(true_peak_db) = true_peak_db.filter(|peak| peak.is_finite()) {
+            gain_db = gain_db.min(MAX_TRUE_PEAK_DB - true_peak_db);
+        }
+        10f64.powf(gain_db / 20.0) as f32
+    }
+}
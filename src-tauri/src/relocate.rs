@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::analysis;
+use crate::db;
+use crate::import;
+
+#[derive(serde::Serialize)]
+pub struct MissingBeat {
+    beat_id: u32,
+    file_path: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct ScanReport {
+    checked: usize,
+    missing: Vec<MissingBeat>,
+    // Beats that were flagged missing but whose file is back.
+    restored: usize,
+}
+
+#[derive(serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMethod {
+    // Only one file with the same name and nothing stored to check it against.
+    Filename,
+    Size,
+    Fingerprint,
+}
+
+#[derive(serde::Serialize)]
+pub struct Relocation {
+    beat_id: u32,
+    old_path: String,
+    new_path: String,
+    matched_by: MatchMethod,
+}
+
+#[derive(serde::Serialize)]
+pub struct RelocateReport {
+    dry_run: bool,
+    relocated: Vec<Relocation>,
+    unmatched: Vec<MissingBeat>,
+}
+
+// Check every beat's file and update its missing flag.
+pub fn scan_library() -> Result<ScanReport, String> {
+    let beats = db::fetch_path_status().map_err(|e| e.to_string())?;
+    let checked = beats.len();
+
+    let mut missing = Vec::new();
+    let mut restored = 0;
+    let mut changes = Vec::new();
+    for (beat_id, file_path, was_missing) in beats {
+        let exists = Path::new(&file_path).is_file();
+        if exists == was_missing {
+            changes.push((beat_id, !exists));
+        }
+        if !exists {
+            missing.push(MissingBeat { beat_id, file_path });
+        } else if was_missing {
+            restored += 1;
+        }
+    }
+    db::set_missing_flags(&changes).map_err(|e| e.to_string())?;
+
+    println!("Library scan: {} checked, {} missing, {} restored", checked, missing.len(), restored);
+    Ok(ScanReport { checked, missing, restored })
+}
+
+// Audio files under the new root that could stand in for a missing beat,
+// with their sizes and fingerprints looked up lazily.
+struct Candidates {
+    by_name: HashMap<String, Vec<PathBuf>>,
+    by_size: HashMap<u64, Vec<PathBuf>>,
+    sizes: HashMap<PathBuf, u64>,
+    fingerprints: HashMap<PathBuf, Option<Vec<u32>>>,
+    claimed: HashSet<PathBuf>,
+}
+
+impl Candidates {
+    fn new(root: &Path, known_paths: &HashSet<String>) -> Self {
+        let mut candidates = Candidates {
+            by_name: HashMap::new(),
+            by_size: HashMap::new(),
+            sizes: HashMap::new(),
+            fingerprints: HashMap::new(),
+            claimed: HashSet::new(),
+        };
+        for file in import::find_audio_files(root) {
+            // Files that already belong to a beat aren't up for grabs.
+            if known_paths.contains(file.to_string_lossy().as_ref()) {
+                continue;
+            }
+            if let Ok(metadata) = fs::metadata(&file) {
+                candidates.sizes.insert(file.clone(), metadata.len());
+                candidates.by_size.entry(metadata.len()).or_default().push(file.clone());
+            }
+            if let Some(name) = file_name_key(&file) {
+                candidates.by_name.entry(name).or_default().push(file);
+            }
+        }
+        candidates
+    }
+
+    fn fingerprint(&mut self, path: &Path) -> Option<&Vec<u32>> {
+        self.fingerprints
+            .entry(path.to_path_buf())
+            .or_insert_with(|| match analysis::fingerprint_file(path) {
+                Ok(fingerprint) => Some(fingerprint),
+                Err(e) => {
+                    eprintln!("Could not fingerprint {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    // Same file name first; if the file was renamed as well, anything with
+    // the same size. Size narrows the list further and the fingerprint
+    // settles whatever is left.
+    fn find_match(&mut self, beat: &db::FileIdentity) -> Option<(PathBuf, MatchMethod)> {
+        let by_name = file_name_key(Path::new(&beat.file_path))
+            .and_then(|name| self.by_name.get(&name))
+            .cloned()
+            .unwrap_or_default();
+        let mut pool: Vec<PathBuf> = if by_name.is_empty() {
+            beat.file_size
+                .and_then(|size| self.by_size.get(&size))
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            by_name
+        };
+        pool.retain(|path| !self.claimed.contains(path));
+        if pool.is_empty() {
+            return None;
+        }
+        // Too-short audio fingerprints to nothing, which can't tell files apart.
+        let stored_fingerprint = beat.fingerprint.as_ref().filter(|f| !f.is_empty());
+
+        if let Some(size) = beat.file_size {
+            let same_size: Vec<PathBuf> = pool
+                .iter()
+                .filter(|path| self.sizes.get(*path) == Some(&size))
+                .cloned()
+                .collect();
+            if same_size.len() == 1 && stored_fingerprint.is_none() {
+                return Some((same_size[0].clone(), MatchMethod::Size));
+            }
+            if !same_size.is_empty() {
+                pool = same_size;
+            }
+        }
+
+        if let Some(stored) = stored_fingerprint {
+            let mut best: Option<(PathBuf, f32)> = None;
+            for path in pool {
+                let Some(candidate) = self.fingerprint(&path) else { continue };
+                let similarity = analysis::fingerprint_similarity(stored, candidate);
+                if similarity >= analysis::FINGERPRINT_MATCH_THRESHOLD && best.as_ref().is_none_or(|b| similarity > b.1) {
+                    best = Some((path, similarity));
+                }
+            }
+            return best.map(|(path, _)| (path, MatchMethod::Fingerprint));
+        }
+
+        // Beats imported before sizes and fingerprints were stored can only be
+        // matched by name, and only when there's no ambiguity.
+        (pool.len() == 1).then(|| (pool[0].clone(), MatchMethod::Filename))
+    }
+}
+
+fn file_name_key(path: &Path) -> Option<String> {
+    path.file_name().map(|name| name.to_string_lossy().to_lowercase())
+}
+
+// Look for every missing beat under `new_root` and rewrite the paths of the
+// ones found in a single transaction. Run `scan_library` first so the missing
+// flags are current.
+pub fn relocate_missing(new_root: &str, dry_run: bool) -> Result<RelocateReport, String> {
+    let root = Path::new(new_root);
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", new_root));
+    }
+
+    let missing = db::fetch_missing_identities().map_err(|e| e.to_string())?;
+    let known_paths: HashSet<String> = db::fetch_file_paths().map_err(|e| e.to_string())?.into_iter().collect();
+    let mut candidates = Candidates::new(root, &known_paths);
+
+    let mut relocated = Vec::new();
+    let mut unmatched = Vec::new();
+    for beat in missing {
+        match candidates.find_match(&beat) {
+            Some((path, matched_by)) => {
+                candidates.claimed.insert(path.clone());
+                relocated.push(Relocation {
+                    beat_id: beat.beat_id,
+                    old_path: beat.file_path,
+                    new_path: path.to_string_lossy().to_string(),
+                    matched_by,
+                });
+            }
+            None => unmatched.push(MissingBeat { beat_id: beat.beat_id, file_path: beat.file_path }),
+        }
+    }
+
+    if !dry_run {
+        let moves: Vec<(u32, String)> = relocated.iter().map(|r| (r.beat_id, r.new_path.clone())).collect();
+        db::relocate_beats(&moves).map_err(|e| e.to_string())?;
+    }
+    println!(
+        "Relocation under {}: {} matched, {} unmatched{}",
+        new_root,
+        relocated.len(),
+        unmatched.len(),
+        if dry_run { " (dry run)" } else { "" }
+    );
+    Ok(RelocateReport { dry_run, relocated, unmatched })
+}
@@ -0,0 +1,199 @@
+use base64::Engine;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::path::Path;
+
+use crate::analysis;
+use crate::db;
+
+const MAX_WIDTH: u32 = 4096;
+const MAX_HEIGHT: u32 = 2048;
+const MIN_FFT_SIZE: usize = 256;
+const MAX_FFT_SIZE: usize = 32768;
+// The bottom of the frequency axis. Nothing below this matters for checking
+// a mix, and a log axis can't start at zero.
+const MIN_FREQUENCY: f32 = 20.0;
+// Levels are drawn from this many dB below full scale up to full scale.
+const DYNAMIC_RANGE_DB: f32 = 120.0;
+// Color stops from silence to full scale, roughly the "inferno" colormap, so
+// a brick-wall cutoff in a fake lossless file stands out against the noise.
+const COLORMAP: [[f32; 3]; 6] = [
+    [0.0, 0.0, 4.0],
+    [40.0, 11.0, 84.0],
+    [101.0, 21.0, 110.0],
+    [187.0, 55.0, 84.0],
+    [249.0, 142.0, 9.0],
+    [252.0, 255.0, 164.0],
+];
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpectrogramFormat {
+    #[default]
+    Png,
+    // The levels themselves, for drawing it in the frontend.
+    Matrix,
+}
+
+#[derive(serde::Serialize)]
+pub struct Spectrogram {
+    width: u32,
+    height: u32,
+    fft_size: usize,
+    sample_rate: u32,
+    duration: f32,
+    // Center frequency of each row, lowest first.
+    frequencies: Vec<f32>,
+    // A data URL, with the highest frequencies at the top.
+    png: Option<String>,
+    // dBFS per row (lowest frequency first), then per column.
+    magnitudes: Option<Vec<Vec<f32>>>,
+}
+
+fn check_size(width: u32, height: u32, fft_size: usize) -> Result<(), String> {
+    if width == 0 || width > MAX_WIDTH || height == 0 || height > MAX_HEIGHT {
+        return Err(format!("Spectrograms can be up to {}x{} pixels", MAX_WIDTH, MAX_HEIGHT));
+    }
+    if !fft_size.is_power_of_two() || !(MIN_FFT_SIZE..=MAX_FFT_SIZE).contains(&fft_size) {
+        return Err(format!(
+            "The FFT size must be a power of two from {} to {}",
+            MIN_FFT_SIZE, MAX_FFT_SIZE
+        ));
+    }
+    Ok(())
+}
+
+// Power spectrum of each column: the STFT frames (Hann window, 50% overlap)
+// falling in it averaged, or the nearest frame when frames are sparser than
+// columns.
+fn column_spectra(samples: &[f32], width: usize, fft_size: usize) -> Vec<Vec<f32>> {
+    let hop = fft_size / 2;
+    let frame_count = samples.len().saturating_sub(fft_size) / hop + 1;
+    let window: Vec<f32> = (0..fft_size)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / fft_size as f32).cos())
+        .collect();
+    // Scaled so a full-scale sine reads 0 dBFS.
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let fft = FftPlanner::new().plan_fft_forward(fft_size);
+
+    let spectrum = |frame: usize| -> Vec<f32> {
+        let start = frame * hop;
+        let mut buffer: Vec<Complex<f32>> = (0..fft_size)
+            .map(|i| Complex::new(samples.get(start + i).copied().unwrap_or(0.0) * window[i], 0.0))
+            .collect();
+        fft.process(&mut buffer);
+        buffer[..fft_size / 2 + 1].iter().map(|bin| (bin.norm() * scale).powi(2)).collect()
+    };
+
+    (0..width)
+        .map(|column| {
+            let first = column * frame_count / width;
+            let last = ((column + 1) * frame_count / width).max(first + 1).min(frame_count);
+            let mut power = vec![0f32; fft_size / 2 + 1];
+            for frame in first..last {
+                for (total, bin) in power.iter_mut().zip(spectrum(frame)) {
+                    *total += bin;
+                }
+            }
+            power.iter_mut().for_each(|total| *total /= (last - first) as f32);
+            power
+        })
+        .collect()
+}
+
+// Resample a linear-frequency power spectrum onto log-spaced rows, taking
+// the loudest bin in each row's range, or interpolating between bins where
+// rows are narrower than a bin.
+fn log_rows(power: &[f32], edges: &[f32], bin_width: f32) -> Vec<f32> {
+    edges
+        .windows(2)
+        .map(|edge| {
+            let (low, high) = (edge[0] / bin_width, edge[1] / bin_width);
+            let first = low.ceil() as usize;
+            let last = (high.floor() as usize).min(power.len() - 1);
+            let value = if first <= last {
+                power[first..=last].iter().copied().fold(0.0, f32::max)
+            } else {
+                let center = ((low + high) / 2.0).min((power.len() - 1) as f32);
+                let index = center.floor() as usize;
+                let next = (index + 1).min(power.len() - 1);
+                let frac = center - index as f32;
+                power[index] * (1.0 - frac) + power[next] * frac
+            };
+            10.0 * value.max(1e-20).log10()
+        })
+        .collect()
+}
+
+fn color(db: f32) -> [u8; 3] {
+    let level = ((db + DYNAMIC_RANGE_DB) / DYNAMIC_RANGE_DB).clamp(0.0, 1.0) * (COLORMAP.len() - 1) as f32;
+    let index = (level.floor() as usize).min(COLORMAP.len() - 2);
+    let frac = level - index as f32;
+    let (from, to) = (COLORMAP[index], COLORMAP[index + 1]);
+    [0, 1, 2].map(|c| (from[c] + (to[c] - from[c]) * frac).round() as u8)
+}
+
+fn encode_png(rows: &[Vec<f32>], width: u32, height: u32) -> Result<String, String> {
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    // Image rows run top to bottom, so from the highest frequency down.
+    for row in rows.iter().rev() {
+        for &db in row {
+            pixels.extend(color(db));
+        }
+    }
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| format!("Failed to encode spectrogram: {}", e))?;
+    writer.write_image_data(&pixels).map_err(|e| format!("Failed to encode spectrogram: {}", e))?;
+    writer.finish().map_err(|e| format!("Failed to encode spectrogram: {}", e))?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(&bytes)))
+}
+
+// A spectrogram of the whole beat, mixed to mono, with time across and a log
+// frequency axis from 20Hz up to the Nyquist frequency.
+pub fn render_spectrogram(
+    beat_id: u32,
+    width: u32,
+    height: u32,
+    fft_size: usize,
+    format: SpectrogramFormat,
+) -> Result<Spectrogram, String> {
+    check_size(width, height, fft_size)?;
+    let beat = db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    let audio = analysis::decode_file(Path::new(beat.file_path()))
+        .map_err(|e| format!("Failed to decode {}: {}", beat.file_path(), e))?;
+    let mono = audio.to_mono();
+    let sample_rate = audio.sample_rate.max(1);
+
+    let bin_width = sample_rate as f32 / fft_size as f32;
+    let nyquist = sample_rate as f32 / 2.0;
+    let low = MIN_FREQUENCY.max(bin_width).min(nyquist / 2.0);
+    let ratio = nyquist / low;
+    let edges: Vec<f32> = (0..=height).map(|i| low * ratio.powf(i as f32 / height as f32)).collect();
+    let frequencies = edges.windows(2).map(|edge| (edge[0] * edge[1]).sqrt()).collect();
+
+    let columns: Vec<Vec<f32>> = column_spectra(&mono, width as usize, fft_size)
+        .iter()
+        .map(|power| log_rows(power, &edges, bin_width))
+        .collect();
+    let rows: Vec<Vec<f32>> = (0..height as usize)
+        .map(|row| columns.iter().map(|column| column[row]).collect())
+        .collect();
+
+    let (png, magnitudes) = match format {
+        SpectrogramFormat::Png => (Some(encode_png(&rows, width, height)?), None),
+        SpectrogramFormat::Matrix => (None, Some(rows)),
+    };
+    Ok(Spectrogram {
+        width,
+        height,
+        fft_size,
+        sample_rate,
+        duration: mono.len() as f32 / sample_rate as f32,
+        frequencies,
+        png,
+        magnitudes,
+    })
+}
@@ -0,0 +1,395 @@
+use rodio::source::SeekError;
+use rodio::Source;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::analysis::Biquad;
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.5;
+pub const MAX_PLAYBACK_RATE: f32 = 2.0;
+pub const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+// Grain length for the time-stretch. Around 40ms keeps drums tight without
+// making bass notes warble.
+const GRAIN_SECONDS: f32 = 0.04;
+// How far either side of its nominal position a grain may start so its
+// waveform lines up with the grain before it.
+const SEARCH_SECONDS: f32 = 0.008;
+// Comparing every fourth frame is plenty to find the best alignment.
+const SEARCH_STRIDE: usize = 4;
+
+// Raising the pitch makes the resampler read the input faster than it plays,
+// so anything above the new Nyquist frequency has to be filtered out first or
+// it folds back down as aliasing. The cutoff sits a little below it, on an
+// eighth-order Butterworth low-pass built from four sections.
+const ANTI_ALIAS_CUTOFF: f64 = 0.45;
+const ANTI_ALIAS_Q: [f64; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedMode {
+    // The tempo changes, the pitch doesn't.
+    #[default]
+    Timestretch,
+    // Tempo and pitch change together, like speeding up a turntable.
+    Vinyl,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Speed {
+    // Playback speed relative to the original tempo.
+    pub rate: f32,
+    pub mode: SpeedMode,
+    // Pitch shift on top of whatever the mode does, keeping the tempo.
+    pub semitones: f32,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed { rate: 1.0, mode: SpeedMode::default(), semitones: 0.0 }
+    }
+}
+
+impl Speed {
+    // The audio is time-stretched by the first factor (changing tempo only)
+    // and then resampled by the second (changing tempo and pitch together).
+    fn factors(&self) -> (f32, f32) {
+        let mut pitch = 2f32.powf(self.semitones / 12.0);
+        if self.mode == SpeedMode::Vinyl {
+            pitch *= self.rate;
+        }
+        (self.rate / pitch, pitch)
+    }
+}
+
+// The speed every playing track follows, shared with the audio thread.
+pub struct SpeedControl {
+    rate: AtomicU32,
+    semitones: AtomicU32,
+    vinyl: AtomicBool,
+}
+
+impl Default for SpeedControl {
+    fn default() -> Self {
+        let control = SpeedControl { rate: AtomicU32::new(0), semitones: AtomicU32::new(0), vinyl: AtomicBool::new(false) };
+        control.set(Speed::default());
+        control
+    }
+}
+
+impl SpeedControl {
+    pub fn set(&self, speed: Speed) {
+        self.rate.store(speed.rate.to_bits(), Ordering::Release);
+        self.semitones.store(speed.semitones.to_bits(), Ordering::Release);
+        self.vinyl.store(speed.mode == SpeedMode::Vinyl, Ordering::Release);
+    }
+
+    fn get(&self) -> Speed {
+        Speed {
+            rate: f32::from_bits(self.rate.load(Ordering::Acquire)),
+            mode: if self.vinyl.load(Ordering::Acquire) { SpeedMode::Vinyl } else { SpeedMode::Timestretch },
+            semitones: f32::from_bits(self.semitones.load(Ordering::Acquire)),
+        }
+    }
+}
+
+// WSOLA time-stretch: Hann-windowed grains are overlap-added at a fixed hop,
+// read from the input at that hop scaled by the tempo, each nudged to where
+// it best continues the waveform of the grain before it.
+struct Stretcher {
+    channels: usize,
+    grain: usize,
+    search: usize,
+    window: Vec<f32>,
+    // Interleaved input frames still needed.
+    input: Vec<f32>,
+    // Where in `input` the input ran out, once it has.
+    end: Option<usize>,
+    // Where the next grain would start at exactly the requested tempo, and
+    // where the input naturally carries on from the last grain.
+    next_pos: f64,
+    continuation: Option<usize>,
+    overlap: Vec<f32>,
+    ready: VecDeque<f32>,
+}
+
+impl Stretcher {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let grain = ((GRAIN_SECONDS * sample_rate as f32) as usize / 2 * 2).max(64);
+        let window = (0..grain)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / grain as f32).cos())
+            .collect();
+        Stretcher {
+            channels,
+            grain,
+            search: (SEARCH_SECONDS * sample_rate as f32) as usize,
+            window,
+            input: Vec::new(),
+            end: None,
+            next_pos: 0.0,
+            continuation: None,
+            overlap: vec![0.0; grain * channels],
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn next_frame<S: Iterator<Item = f32>>(&mut self, inner: &mut S, tempo: f32, frame: &mut [f32]) -> bool {
+        while self.ready.len() < self.channels {
+            if !self.add_grain(inner, tempo) {
+                return false;
+            }
+        }
+        for sample in frame.iter_mut() {
+            *sample = self.ready.pop_front().unwrap_or(0.0);
+        }
+        true
+    }
+
+    fn add_grain<S: Iterator<Item = f32>>(&mut self, inner: &mut S, tempo: f32) -> bool {
+        let channels = self.channels;
+        let half = self.grain / 2;
+        let nominal = self.next_pos as usize;
+        let needed = (nominal + self.search + self.grain) * channels;
+        while self.end.is_none() && self.input.len() < needed {
+            match inner.next() {
+                Some(sample) => self.input.push(sample),
+                None => self.end = Some(self.input.len() / channels),
+            }
+        }
+        if self.end.is_some_and(|end| nominal >= end) {
+            return false;
+        }
+        // Past the end of the input the last grains read silence.
+        if self.input.len() < needed {
+            self.input.resize(needed, 0.0);
+        }
+
+        let start = match self.continuation {
+            None => nominal,
+            // At the original tempo the natural continuation is exact.
+            Some(continuation) if (tempo - 1.0).abs() < 1e-4 => continuation,
+            Some(continuation) => self.best_start(continuation, nominal),
+        };
+        for i in 0..self.grain {
+            let weight = self.window[i];
+            for c in 0..channels {
+                self.overlap[i * channels + c] += self.input[(start + i) * channels + c] * weight;
+            }
+        }
+        self.ready.extend(self.overlap.drain(..half * channels));
+        self.overlap.resize(self.grain * channels, 0.0);
+        let continuation = start + half;
+        self.next_pos = if (tempo - 1.0).abs() < 1e-4 {
+            continuation as f64
+        } else {
+            self.next_pos + half as f64 * tempo as f64
+        };
+
+        // Drop input no later grain can reach.
+        let keep_from = continuation.min((self.next_pos as usize).saturating_sub(self.search));
+        self.input.drain(..keep_from * channels);
+        self.continuation = Some(continuation - keep_from);
+        self.next_pos -= keep_from as f64;
+        self.end = self.end.map(|end| end.saturating_sub(keep_from));
+        true
+    }
+
+    // The start near `nominal` whose first half best matches the input that
+    // naturally follows the previous grain, by normalized cross-correlation
+    // of the channels summed to mono.
+    fn best_start(&self, target: usize, nominal: usize) -> usize {
+        let half = self.grain / 2;
+        let low = nominal.saturating_sub(self.search);
+        let high = nominal + self.search;
+        let mono = |frame: usize| -> f32 {
+            self.input[frame * self.channels..(frame + 1) * self.channels].iter().sum()
+        };
+        let reference: Vec<f32> = (0..half).step_by(SEARCH_STRIDE).map(|i| mono(target + i)).collect();
+        let candidates: Vec<f32> = (low..high + half).map(mono).collect();
+
+        let mut best = (nominal, f32::MIN);
+        for start in low..=high {
+            let (mut dot, mut energy) = (0.0, 1e-9);
+            for (j, reference) in reference.iter().enumerate() {
+                let sample = candidates[start - low + j * SEARCH_STRIDE];
+                dot += sample * reference;
+                energy += sample * sample;
+            }
+            let score = dot / energy.sqrt();
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+}
+
+// Plays a source at the speed set on a `SpeedControl`, which can change
+// while it plays.
+pub struct Stretch<S> {
+    inner: S,
+    control: Arc<SpeedControl>,
+    channels: usize,
+    sample_rate: u32,
+    // Only set up once the tempo has to change independently of the pitch,
+    // and kept from then on so toggling it doesn't skip audio.
+    stretcher: Option<Stretcher>,
+    // Per-channel low-pass ahead of the resampler and the pitch it's tuned
+    // for, only while the pitch is raised.
+    anti_alias: Option<(f32, Vec<[Biquad; 4]>)>,
+    // Linear-interpolation resampler between the two most recent frames.
+    previous: Vec<f32>,
+    current: Vec<f32>,
+    fraction: f64,
+    primed: bool,
+    frame: Vec<f32>,
+    channel: usize,
+}
+
+impl<S> Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<SpeedControl>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        Stretch {
+            inner,
+            control,
+            channels,
+            sample_rate,
+            stretcher: None,
+            anti_alias: None,
+            previous: vec![0.0; channels],
+            current: vec![0.0; channels],
+            fraction: 0.0,
+            primed: false,
+            frame: vec![0.0; channels],
+            channel: 0,
+        }
+    }
+
+    // The next frame at the stretched tempo, before resampling.
+    fn pull(&mut self, tempo: f32, frame: &mut [f32]) -> bool {
+        if self.stretcher.is_none() && (tempo - 1.0).abs() >= 1e-4 {
+            self.stretcher = Some(Stretcher::new(self.channels, self.sample_rate));
+        }
+        match &mut self.stretcher {
+            Some(stretcher) => stretcher.next_frame(&mut self.inner, tempo, frame),
+            None => {
+                for sample in frame.iter_mut() {
+                    match self.inner.next() {
+                        Some(value) => *sample = value,
+                        None => return false,
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    // Low-pass a frame about to be resampled by `pitch`.
+    fn anti_alias(&mut self, pitch: f32, frame: &mut [f32]) {
+        if pitch <= 1.0 {
+            self.anti_alias = None;
+            return;
+        }
+        let sample_rate = self.sample_rate;
+        let design = |q| Biquad::low_pass(sample_rate, ANTI_ALIAS_CUTOFF * sample_rate as f64 / pitch as f64, q);
+        let (tuned, filters) = self
+            .anti_alias
+            .get_or_insert_with(|| (pitch, vec![ANTI_ALIAS_Q.map(design); frame.len()]));
+        if *tuned != pitch {
+            for cascade in filters.iter_mut() {
+                for (filter, q) in cascade.iter_mut().zip(ANTI_ALIAS_Q) {
+                    filter.retune(&design(q));
+                }
+            }
+            *tuned = pitch;
+        }
+        for (sample, cascade) in frame.iter_mut().zip(filters) {
+            *sample = cascade.iter_mut().fold(*sample as f64, |value, filter| filter.process(value)) as f32;
+        }
+    }
+
+    fn next_frame(&mut self) -> bool {
+        let (tempo, pitch) = self.control.get().factors();
+        let mut current = std::mem::take(&mut self.current);
+        if !self.primed {
+            if !self.pull(tempo, &mut current) {
+                self.current = current;
+                return false;
+            }
+            self.anti_alias(pitch, &mut current);
+            self.previous.copy_from_slice(&current);
+            self.primed = true;
+        }
+        while self.fraction >= 1.0 {
+            std::mem::swap(&mut self.previous, &mut current);
+            if !self.pull(tempo, &mut current) {
+                self.current = current;
+                return false;
+            }
+            self.anti_alias(pitch, &mut current);
+            self.fraction -= 1.0;
+        }
+        let fraction = self.fraction as f32;
+        for (c, sample) in self.frame.iter_mut().enumerate() {
+            *sample = self.previous[c] + (current[c] - self.previous[c]) * fraction;
+        }
+        self.current = current;
+        self.fraction += pitch as f64;
+        true
+    }
+}
+
+impl<S> Iterator for Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 && !self.next_frame() {
+            return None;
+        }
+        let sample = self.frame[self.channel];
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample)
+    }
+}
+
+impl<S> Source for Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Depends on the speed, which can change at any time.
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.stretcher = None;
+        self.anti_alias = None;
+        self.primed = false;
+        self.fraction = 0.0;
+        self.channel = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::file::FileType;
+use lofty::flac::FlacFile;
+use lofty::id3::v2::{Frame, FrameId, Id3v2Tag, TextInformationFrame};
+use lofty::iff::wav::{RiffInfoList, WavFile};
+use lofty::mpeg::MpegFile;
+use lofty::ogg::tag::VorbisComments;
+use lofty::prelude::*;
+use lofty::probe::Probe;
+use lofty::tag::Tag;
+use lofty::TextEncoding;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::meta::{MetadataRevision, StandardTagKey};
+use symphonia::core::probe::ProbeResult;
+
+use crate::analysis;
+
+// The subset of embedded tags beatbank cares about. Every field is optional,
+// callers fall back to filename-derived values or analysis when it's missing.
+#[derive(Debug, Default, Clone)]
+pub struct TrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<f32>,
+    pub key: Option<String>,
+}
+
+impl TrackTags {
+    fn apply(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string().trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                Some(StandardTagKey::Genre) => self.genre = Some(value),
+                Some(StandardTagKey::Comment) => self.comment = Some(value),
+                Some(StandardTagKey::Bpm) => self.bpm = parse_bpm(&value).or(self.bpm),
+                _ => {
+                    // Symphonia has no standard key for the initial key, so
+                    // match the raw ID3v2 frame / Vorbis comment names.
+                    let key = tag.key.to_ascii_uppercase();
+                    if key == "TKEY" || key == "INITIALKEY" || key == "KEY" {
+                        self.key = Some(value);
+                    } else if key == "TEMPO" && self.bpm.is_none() {
+                        self.bpm = parse_bpm(&value);
+                    }
+                }
+            }
+        }
+    }
+
+
+    // Fill in whatever symphonia didn't find from lofty's view of the file.
+    fn fill_missing(&mut self, tag: &Tag) {
+        let text = |key: ItemKey| tag.get_string(key).map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        self.title = self.title.take().or_else(|| text(ItemKey::TrackTitle));
+        self.artist = self.artist.take().or_else(|| text(ItemKey::TrackArtist));
+        self.album = self.album.take().or_else(|| text(ItemKey::AlbumTitle));
+        self.genre = self.genre.take().or_else(|| text(ItemKey::Genre));
+        self.comment = self.comment.take().or_else(|| text(ItemKey::Comment));
+        self.key = self.key.take().or_else(|| text(ItemKey::InitialKey));
+        self.bpm = self.bpm.or_else(|| {
+            text(ItemKey::IntegerBpm)
+                .or_else(|| text(ItemKey::Bpm))
+                .and_then(|v| parse_bpm(&v))
+        });
+    }
+}
+
+// TBPM is meant to be an integer but taggers happily write "128.00" or "0".
+fn parse_bpm(value: &str) -> Option<f32> {
+    value.parse::<f32>().ok().filter(|bpm| *bpm > 0.0 && bpm.is_finite())
+}
+
+// Read tags from a probed file. Tags found in front of the container (e.g. an
+// ID3v2 block) are read first and the container's own metadata (Vorbis
+// comments, RIFF INFO) wins when both define the same field.
+//
+// Symphonia stops reading a WAV file at its data chunk and ignores ID3
+// chunks, which is exactly where most taggers (and `write_tags`) put them,
+// so any gaps are filled in by lofty afterwards.
+pub fn read_tags(path: &Path, probed: &mut ProbeResult) -> TrackTags {
+    let mut tags = TrackTags::default();
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.skip_to_latest() {
+            tags.apply(revision);
+        }
+    }
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        tags.apply(revision);
+    }
+
+    match lofty::read_from_path(path) {
+        Ok(tagged_file) => {
+            for tag in tagged_file.tags() {
+                tags.fill_missing(tag);
+            }
+        }
+        Err(e) => eprintln!("Could not read tags from {} with lofty: {}", path.display(), e),
+    }
+    tags
+}
+
+// What beatbank writes back into a file after a beat is edited.
+pub struct TagValues {
+    pub title: String,
+    pub artist: String,
+    pub bpm: u32,
+    pub musical_key: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct TagChange {
+    tag: String,
+    field: String,
+    current: Option<String>,
+    new: String,
+}
+
+// A tag as it sits in the file. Only the items beatbank writes are touched,
+// so every other frame and field is saved exactly as it was read. Going
+// through lofty's generic `Tag` would drop anything it has no `ItemKey` for.
+enum FileTag<'a> {
+    Id3v2(&'a mut Id3v2Tag),
+    VorbisComments(&'a mut VorbisComments),
+    RiffInfo(&'a mut RiffInfoList),
+}
+
+impl FileTag<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            FileTag::Id3v2(_) => "Id3v2",
+            FileTag::VorbisComments(_) => "VorbisComments",
+            FileTag::RiffInfo(_) => "RiffInfo",
+        }
+    }
+
+    // The tag's own key for a field. RIFF INFO has no BPM or key fields,
+    // those only go in the ID3 chunk.
+    fn key_for(&self, field: &str) -> Option<&'static str> {
+        match (self, field) {
+            (FileTag::Id3v2(_), "title") => Some("TIT2"),
+            (FileTag::Id3v2(_), "artist") => Some("TPE1"),
+            // TBPM is integer-only, which is all beatbank stores anyway.
+            (FileTag::Id3v2(_), "bpm") => Some("TBPM"),
+            (FileTag::Id3v2(_), "key") => Some("TKEY"),
+            (FileTag::VorbisComments(_), "title") => Some("TITLE"),
+            (FileTag::VorbisComments(_), "artist") => Some("ARTIST"),
+            (FileTag::VorbisComments(_), "bpm") => Some("BPM"),
+            (FileTag::VorbisComments(_), "key") => Some("INITIALKEY"),
+            (FileTag::RiffInfo(_), "title") => Some("INAM"),
+            (FileTag::RiffInfo(_), "artist") => Some("IART"),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let value = match self {
+            FileTag::Id3v2(tag) => tag.get_text(&FrameId::Valid(Cow::Borrowed(key))),
+            FileTag::VorbisComments(tag) => tag.get(key),
+            FileTag::RiffInfo(tag) => tag.get(key),
+        };
+        value.map(str::to_string)
+    }
+
+    fn insert(&mut self, key: &'static str, value: String) {
+        match self {
+            FileTag::Id3v2(tag) => {
+                let frame = TextInformationFrame::new(FrameId::Valid(Cow::Borrowed(key)), TextEncoding::UTF8, value);
+                tag.insert(Frame::Text(frame));
+            }
+            FileTag::VorbisComments(tag) => tag.insert(key.to_string(), value),
+            FileTag::RiffInfo(tag) => tag.insert(key.to_string(), value),
+        }
+    }
+
+    // Set every field of `values` that differs, recording each change.
+    fn update(&mut self, values: &TagValues, changes: &mut Vec<TagChange>) {
+        let key = analysis::MusicalKey::parse(&values.musical_key).map(|k| k.short());
+        let fields = [
+            ("title", values.title.trim().to_string()),
+            ("artist", values.artist.trim().to_string()),
+            ("bpm", if values.bpm > 0 { values.bpm.to_string() } else { String::new() }),
+            ("key", key.unwrap_or_default()),
+        ];
+        for (field, new) in fields {
+            let Some(item_key) = self.key_for(field).filter(|_| !new.is_empty()) else {
+                continue;
+            };
+            let current = self.get(item_key);
+            if current.as_deref() == Some(new.as_str()) {
+                continue;
+            }
+            changes.push(TagChange {
+                tag: self.name().to_string(),
+                field: field.to_string(),
+                current,
+                new: new.clone(),
+            });
+            self.insert(item_key, new);
+        }
+    }
+}
+
+// Compare the file's tags against `values` and, unless `dry_run` is set,
+// write the differences back. Returns every field that differs (or differed).
+// Empty values, a zero BPM and an unknown key are never written, so an edit
+// can't wipe out tags that beatbank simply doesn't know about.
+//
+// MP3 files keep an ID3v2 tag and FLAC files Vorbis comments in sync. WAV
+// files get both an ID3 chunk (the only place BPM and key can live) and a RIFF
+// INFO list, since that's what most other tools read for title and artist.
+pub fn write_tags(path: &Path, values: &TagValues, dry_run: bool) -> Result<Vec<TagChange>, Box<dyn Error>> {
+    let file_type = Probe::open(path)?.guess_file_type()?.file_type();
+    let mut reader = File::open(path)?;
+    let options = ParseOptions::new();
+
+    let mut changes = Vec::new();
+    match file_type {
+        Some(FileType::Mpeg) => {
+            let mut file = MpegFile::read_from(&mut reader, options)?;
+            let mut id3v2 = file.remove_id3v2().unwrap_or_default();
+            FileTag::Id3v2(&mut id3v2).update(values, &mut changes);
+            file.set_id3v2(id3v2);
+            save(&file, path, &changes, dry_run)?;
+        }
+        Some(FileType::Flac) => {
+            let mut file = FlacFile::read_from(&mut reader, options)?;
+            let mut comments = file.remove_vorbis_comments().unwrap_or_default();
+            FileTag::VorbisComments(&mut comments).update(values, &mut changes);
+            file.set_vorbis_comments(comments);
+            save(&file, path, &changes, dry_run)?;
+        }
+        Some(FileType::Wav) => {
+            let mut file = WavFile::read_from(&mut reader, options)?;
+            let mut id3v2 = file.remove_id3v2().unwrap_or_default();
+            let mut riff_info = file.remove_riff_info().unwrap_or_default();
+            FileTag::Id3v2(&mut id3v2).update(values, &mut changes);
+            FileTag::RiffInfo(&mut riff_info).update(values, &mut changes);
+            file.set_id3v2(id3v2);
+            file.set_riff_info(riff_info);
+            save(&file, path, &changes, dry_run)?;
+        }
+        other => return Err(format!("Writing tags is not supported for {:?} files", other).into()),
+    }
+    Ok(changes)
+}
+
+// Write the whole file back, so tags and pictures stored outside the edited
+// tags are kept too.
+fn save(file: &impl AudioFile, path: &Path, changes: &[TagChange], dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if !changes.is_empty() && !dry_run {
+        file.save_to_path(path, WriteOptions::default())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use rodio::source::SeekError;
+use rodio::Source;
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Loops up to this long are kept in memory after their first pass so they
+// wrap without touching the decoder again. Longer ones wrap by seeking.
+const MAX_CACHED_LOOP_SECONDS: u64 = 60;
+
+// Shared between the audio thread and a playing `Track`.
+pub struct TrackControl {
+    started: AtomicBool,
+    cancelled: AtomicBool,
+    // Fade out over this many microseconds, starting from the next frame
+    // played. Zero means no fade out.
+    fade_out_micros: AtomicU64,
+    // Loop region, only played as a loop while enabled and the end is past
+    // the start.
+    loop_start_micros: AtomicU64,
+    loop_end_micros: AtomicU64,
+    loop_enabled: AtomicBool,
+    // Where in the file playback is. Rodio's own position keeps counting up
+    // through a loop, so the track reports it itself.
+    position_micros: AtomicU64,
+    // Level the track plays at, as the bits of an f32 linear gain, such as
+    // the loudness normalization gain.
+    level: AtomicU32,
+}
+
+impl Default for TrackControl {
+    fn default() -> Self {
+        TrackControl {
+            started: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            fade_out_micros: AtomicU64::new(0),
+            loop_start_micros: AtomicU64::new(0),
+            loop_end_micros: AtomicU64::new(0),
+            loop_enabled: AtomicBool::new(false),
+            position_micros: AtomicU64::new(0),
+            level: AtomicU32::new(1f32.to_bits()),
+        }
+    }
+}
+
+impl TrackControl {
+    pub fn has_started(&self) -> bool {
+        self.started.load(Ordering::Acquire)
+    }
+
+    // Makes a track that hasn't been reached yet end as soon as it is, so it
+    // can be taken back out of a sink's queue.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn fade_out(&self, length: Duration) {
+        self.fade_out_micros.store(length.as_micros() as u64, Ordering::Release);
+    }
+
+    pub fn set_loop(&self, start: Duration, end: Duration, enabled: bool) {
+        self.loop_start_micros.store(start.as_micros() as u64, Ordering::Release);
+        self.loop_end_micros.store(end.as_micros() as u64, Ordering::Release);
+        self.loop_enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn set_level(&self, gain: f32) {
+        self.level.store(gain.to_bits(), Ordering::Release);
+    }
+
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.position_micros.load(Ordering::Acquire))
+    }
+
+    fn loop_frames(&self, sample_rate: u32) -> Option<(u64, u64)> {
+        let start = micros_to_frames(self.loop_start_micros.load(Ordering::Acquire), sample_rate);
+        let end = micros_to_frames(self.loop_end_micros.load(Ordering::Acquire), sample_rate);
+        (end > start).then_some((start, end))
+    }
+}
+
+fn micros_to_frames(micros: u64, sample_rate: u32) -> u64 {
+    micros * sample_rate as u64 / 1_000_000
+}
+
+fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    Duration::from_micros(frames * 1_000_000 / sample_rate.max(1) as u64)
+}
+
+// The samples of a loop region, recorded the first time it plays through.
+struct LoopCache {
+    start: u64,
+    end: u64,
+    samples: Vec<f32>,
+    complete: bool,
+}
+
+// A decoded track with an equal-power fade in at its start, a fade out that
+// ends the track once requested through its `TrackControl`, and an optional
+// loop region that wraps on the exact frame.
+pub struct Track<S> {
+    inner: S,
+    control: Arc<TrackControl>,
+    // Frames played so far, and the sample within the current frame.
+    frame: u64,
+    channel: u16,
+    fade_in_frames: u64,
+    fade_out_start: Option<u64>,
+    gain: f32,
+    loop_cache: Option<LoopCache>,
+    // Set while playing from `loop_cache` rather than the decoder.
+    cache_pos: Option<usize>,
+}
+
+impl<S> Track<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, fade_in: Duration) -> (Self, Arc<TrackControl>) {
+        let control = Arc::new(TrackControl::default());
+        let fade_in_frames = (fade_in.as_secs_f64() * inner.sample_rate() as f64) as u64;
+        let track = Track {
+            inner,
+            control: control.clone(),
+            frame: 0,
+            channel: 0,
+            fade_in_frames,
+            fade_out_start: None,
+            gain: 1.0,
+            loop_cache: None,
+            cache_pos: None,
+        };
+        (track, control)
+    }
+
+    // Gain for the frame about to be played, or None once a fade out is over.
+    fn frame_gain(&mut self) -> Option<f32> {
+        let mut gain = f32::from_bits(self.control.level.load(Ordering::Acquire));
+        if self.frame < self.fade_in_frames {
+            gain *= (FRAC_PI_2 * self.frame as f32 / self.fade_in_frames as f32).sin();
+        }
+        let fade_out_micros = self.control.fade_out_micros.load(Ordering::Acquire);
+        let fade_out_frames = micros_to_frames(fade_out_micros, self.inner.sample_rate());
+        if fade_out_frames == 0 {
+            self.fade_out_start = None;
+        } else {
+            let start = *self.fade_out_start.get_or_insert(self.frame);
+            let progress = (self.frame - start) as f32 / fade_out_frames as f32;
+            if progress >= 1.0 {
+                return None;
+            }
+            gain *= (FRAC_PI_2 * progress).cos();
+        }
+        Some(gain)
+    }
+
+    // Called on every frame boundary: jump back to the loop start when the
+    // loop end is reached, and start recording the region as it's entered.
+    fn apply_loop(&mut self) {
+        let sample_rate = self.inner.sample_rate();
+        let bounds = self.control.loop_frames(sample_rate);
+        if self.loop_cache.as_ref().is_some_and(|cache| Some((cache.start, cache.end)) != bounds) {
+            self.leave_cache();
+            self.loop_cache = None;
+        }
+        let enabled = self.control.loop_enabled.load(Ordering::Acquire);
+        let Some((start, end)) = bounds.filter(|_| enabled) else {
+            self.leave_cache();
+            return;
+        };
+
+        let channels = self.inner.channels() as usize;
+        if let Some(cache) = self.loop_cache.as_mut() {
+            if !cache.complete && cache.samples.len() == (end - start) as usize * channels {
+                cache.complete = true;
+            }
+        }
+
+        if self.frame >= end {
+            self.frame = start;
+            if self.loop_cache.as_ref().is_some_and(|cache| cache.complete) {
+                self.cache_pos = Some(0);
+            } else {
+                self.cache_pos = None;
+                if let Err(e) = self.inner.try_seek(frames_to_duration(start, sample_rate)) {
+                    eprintln!("Failed to wrap loop: {}", e);
+                }
+            }
+        }
+
+        let fits = end - start <= MAX_CACHED_LOOP_SECONDS * sample_rate as u64;
+        if self.loop_cache.is_none() && self.frame == start && fits {
+            self.loop_cache = Some(LoopCache {
+                start,
+                end,
+                samples: Vec::with_capacity((end - start) as usize * channels),
+                complete: false,
+            });
+        }
+    }
+
+    // Go back to reading from the decoder at the current frame.
+    fn leave_cache(&mut self) {
+        if self.cache_pos.take().is_some() {
+            if let Err(e) = self.inner.try_seek(frames_to_duration(self.frame, self.inner.sample_rate())) {
+                eprintln!("Failed to leave loop: {}", e);
+            }
+        }
+    }
+
+    fn next_sample(&mut self) -> Option<f32> {
+        if let Some(pos) = self.cache_pos {
+            let cache = self.loop_cache.as_ref()?;
+            self.cache_pos = Some(pos + 1);
+            return cache.samples.get(pos).copied();
+        }
+        let sample = self.inner.next()?;
+        if let Some(cache) = self.loop_cache.as_mut().filter(|cache| !cache.complete) {
+            // Only record while playback runs straight through the region.
+            let expected = (self.frame.saturating_sub(cache.start)) as usize * self.inner.channels() as usize
+                + self.channel as usize;
+            if self.frame >= cache.start && self.frame < cache.end && cache.samples.len() == expected {
+                cache.samples.push(sample);
+            }
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Iterator for Track<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.control.cancelled.load(Ordering::Acquire) {
+            return None;
+        }
+        self.control.started.store(true, Ordering::Release);
+        if self.channel == 0 {
+            self.apply_loop();
+            self.gain = self.frame_gain()?;
+            let micros = self.frame * 1_000_000 / self.inner.sample_rate().max(1) as u64;
+            self.control.position_micros.store(micros, Ordering::Release);
+        }
+        let sample = self.next_sample()?;
+        self.channel += 1;
+        if self.channel >= self.inner.channels() {
+            self.channel = 0;
+            self.frame += 1;
+        }
+        Some(sample * self.gain)
+    }
+}
+
+impl<S> Source for Track<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        // Wrapping a loop breaks the decoder's frame boundaries.
+        if self.loop_cache.is_some() || self.control.loop_enabled.load(Ordering::Acquire) {
+            return Some(1024);
+        }
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.cache_pos = None;
+        self.inner.try_seek(pos)?;
+        self.frame = (pos.as_secs_f64() * self.inner.sample_rate() as f64) as u64;
+        self.channel = 0;
+        // Seeking back into the middle of a fade out starts it over.
+        self.fade_out_start = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+// How one queued track hands over to the next.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Transition {
+    // The next track starts once the current one has finished and the sink
+    // has run dry, with whatever gap that leaves.
+    #[default]
+    Cut,
+    // The next track is queued on the same sink ahead of time so its first
+    // sample follows the last sample of the current one.
+    Gapless,
+    // Equal-power crossfade over the end of the current track.
+    Crossfade { length: f32, unit: FadeUnit },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FadeUnit {
+    Seconds,
+    Beats,
+}
+
+impl Transition {
+    // Crossfade length in seconds. Lengths in beats need the outgoing track's
+    // tempo, without one there's nothing to fade over.
+    pub fn fade_seconds(&self, bpm: Option<f32>) -> Option<f32> {
+        match *self {
+            Transition::Crossfade { length, unit: FadeUnit::Seconds } => Some(length),
+            Transition::Crossfade { length, unit: FadeUnit::Beats } => {
+                bpm.filter(|bpm| *bpm > 0.0).map(|bpm| length * 60.0 / bpm)
+            }
+            _ => None,
+        }
+        .filter(|seconds| *seconds > 0.0)
+    }
+}
@@ -0,0 +1,275 @@
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+use crate::db;
+use crate::import;
+
+const LIBRARY_CHANGED_EVENT: &str = "library-changed";
+
+// DAWs write bounces in chunks, so a new file is only imported once it has
+// gone this long without being modified.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+static WATCHER: Lazy<Mutex<Option<RecommendedWatcher>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(serde::Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum LibraryChange {
+    Imported { file_path: String },
+    Restored { file_path: String },
+    Renamed { from: String, to: String },
+    Missing { file_path: String },
+}
+
+// Start the watcher thread and begin watching every folder stored in the
+// database. Each folder also gets a catch-up import for files that appeared
+// while beatbank wasn't running.
+pub fn start(app_handle: AppHandle) -> Result<(), String> {
+    let (sender, receiver) = channel();
+    let watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })
+    .map_err(|e| format!("Failed to create folder watcher: {}", e))?;
+    *WATCHER.lock().unwrap() = Some(watcher);
+
+    let handle = app_handle.clone();
+    thread::spawn(move || event_loop(handle, receiver));
+
+    let folders = db::get_watched_folders().map_err(|e| e.to_string())?;
+    for folder in folders {
+        if let Err(e) = watch_path(&folder.path) {
+            eprintln!("{}", e);
+            continue;
+        }
+        if let Err(e) = import::import_folder(app_handle.clone(), folder.path.clone()) {
+            eprintln!("Catch-up import of {} failed: {}", folder.path, e);
+        }
+    }
+    Ok(())
+}
+
+pub fn add_folder(app_handle: AppHandle, path: String) -> Result<i64, String> {
+    if !Path::new(&path).is_dir() {
+        return Err(format!("{} is not a folder", path));
+    }
+    // The row goes in first so a folder that's already watched is turned
+    // away before touching the watcher, and comes back out if watching fails.
+    let folder_id = db::add_watched_folder(&path).map_err(|e| e.to_string())?;
+    if let Err(e) = watch_path(&path) {
+        if let Err(e) = db::remove_watched_folder(folder_id) {
+            eprintln!("Failed to forget unwatched folder {}: {}", path, e);
+        }
+        return Err(e);
+    }
+    import::import_folder(app_handle, path)?;
+    Ok(folder_id)
+}
+
+pub fn remove_folder(folder_id: i64) -> Result<(), String> {
+    let path = db::remove_watched_folder(folder_id).map_err(|e| e.to_string())?;
+    if let Some(watcher) = WATCHER.lock().unwrap().as_mut() {
+        // The folder may already be gone from disk, which is fine.
+        if let Err(e) = watcher.unwatch(Path::new(&path)) {
+            eprintln!("Failed to stop watching {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+fn watch_path(path: &str) -> Result<(), String> {
+    let mut watcher = WATCHER.lock().unwrap();
+    let watcher = watcher.as_mut().ok_or("Folder watcher is not running")?;
+    watcher
+        .watch(Path::new(path), RecursiveMode::Recursive)
+        .map_err(|e| format!("Failed to watch {}: {}", path, e))
+}
+
+#[derive(Default)]
+struct WatchState {
+    // New or still-changing files, keyed to when they were last touched.
+    pending_imports: HashMap<PathBuf, Instant>,
+    // Half of a rename reported as two events, waiting for its other half.
+    pending_rename: Option<(PathBuf, Instant)>,
+    // inotify reports a rename as From, To and then Both; remember the last
+    // one handled so the trailing Both is a no-op.
+    last_rename: Option<(PathBuf, PathBuf)>,
+}
+
+fn event_loop(app_handle: AppHandle, receiver: Receiver<notify::Result<Event>>) {
+    let mut state = WatchState::default();
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => state.handle(&app_handle, event),
+            Ok(Err(e)) => eprintln!("Folder watcher error: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        state.flush(&app_handle);
+    }
+}
+
+impl WatchState {
+    fn handle(&mut self, app_handle: &AppHandle, event: Event) {
+        let now = Instant::now();
+        match event.kind {
+            EventKind::Create(_) => {
+                for path in event.paths {
+                    self.queue_import(path, now);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                self.rename(app_handle, event.paths[0].clone(), event.paths[1].clone(), now);
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                if let Some(path) = event.paths.into_iter().next() {
+                    self.pending_rename = Some((path, now));
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                if let Some(to) = event.paths.into_iter().next() {
+                    match self.pending_rename.take() {
+                        Some((from, _)) => self.rename(app_handle, from, to, now),
+                        None => self.queue_import(to, now),
+                    }
+                }
+            }
+            // Some platforms can't tell which side of a rename this is, so
+            // go by whether the path still exists.
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in event.paths {
+                    if path.exists() {
+                        self.queue_import(path, now);
+                    } else {
+                        self.mark_missing(app_handle, &path);
+                    }
+                }
+            }
+            EventKind::Modify(_) => {
+                for path in event.paths {
+                    if let Some(touched) = self.pending_imports.get_mut(&path) {
+                        *touched = now;
+                    }
+                }
+            }
+            EventKind::Remove(_) => {
+                for path in event.paths {
+                    self.pending_imports.remove(&path);
+                    self.mark_missing(app_handle, &path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Queue a created file, or every audio file inside a created folder.
+    fn queue_import(&mut self, path: PathBuf, now: Instant) {
+        if path.is_dir() {
+            for file in import::find_audio_files(&path) {
+                self.pending_imports.insert(file, now);
+            }
+        } else if import::is_supported_audio(&path) {
+            self.pending_imports.insert(path, now);
+        }
+    }
+
+    fn rename(&mut self, app_handle: &AppHandle, from: PathBuf, to: PathBuf, now: Instant) {
+        if self.last_rename.as_ref() == Some(&(from.clone(), to.clone())) {
+            return;
+        }
+        self.last_rename = Some((from.clone(), to.clone()));
+
+        // Renamed before it finished settling, follow it to its new name.
+        if self.pending_imports.remove(&from).is_some() {
+            self.queue_import(to, now);
+            return;
+        }
+
+        let (from_str, to_str) = (from.to_string_lossy().to_string(), to.to_string_lossy().to_string());
+        match db::rename_file_paths(&from_str, &to_str) {
+            Ok(0) => {
+                // Nothing we knew about, e.g. a DAW renaming its temp file into place.
+                self.queue_import(to, now);
+            }
+            Ok(count) => {
+                println!("Updated {} beat path(s) after rename {} -> {}", count, from_str, to_str);
+                emit(app_handle, LibraryChange::Renamed { from: from_str, to: to_str });
+            }
+            Err(e) => eprintln!("Failed to update paths after rename {} -> {}: {}", from_str, to_str, e),
+        }
+    }
+
+    fn mark_missing(&mut self, app_handle: &AppHandle, path: &Path) {
+        let file_path = path.to_string_lossy().to_string();
+        match db::set_missing_by_path(&file_path, true) {
+            Ok(0) => {}
+            Ok(count) => {
+                println!("Flagged {} beat(s) as missing under {}", count, file_path);
+                emit(app_handle, LibraryChange::Missing { file_path });
+            }
+            Err(e) => eprintln!("Failed to flag {} as missing: {}", file_path, e),
+        }
+    }
+
+    fn flush(&mut self, app_handle: &AppHandle) {
+        let now = Instant::now();
+
+        // A rename whose other half never arrived moved out of the watched
+        // folders, which from our side is a deletion.
+        if let Some((from, since)) = self.pending_rename.take() {
+            if now.duration_since(since) >= SETTLE_TIME {
+                self.mark_missing(app_handle, &from);
+            } else {
+                self.pending_rename = Some((from, since));
+            }
+        }
+
+        let settled: Vec<PathBuf> = self
+            .pending_imports
+            .iter()
+            .filter(|(_, touched)| now.duration_since(**touched) >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            self.pending_imports.remove(&path);
+            import_file(app_handle, &path);
+        }
+    }
+}
+
+fn import_file(app_handle: &AppHandle, path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+    let file_path = path.to_string_lossy().to_string();
+    match db::find_beat_by_path(&file_path) {
+        // A file we flagged as missing has come back.
+        Ok(Some((_, true))) => match db::set_missing_by_path(&file_path, false) {
+            Ok(_) => emit(app_handle, LibraryChange::Restored { file_path }),
+            Err(e) => eprintln!("Failed to restore {}: {}", file_path, e),
+        },
+        Ok(Some((_, false))) => {}
+        Ok(None) => {
+            println!("Watched folder: importing {}", file_path);
+            match db::add_beat(file_path.clone()) {
+                Ok(()) => emit(app_handle, LibraryChange::Imported { file_path }),
+                Err(e) => eprintln!("Failed to import {}: {}", file_path, e),
+            }
+        }
+        Err(e) => eprintln!("Failed to look up {}: {}", file_path, e),
+    }
+}
+
+fn emit(app_handle: &AppHandle, change: LibraryChange) {
+    if let Err(e) = app_handle.emit_all(LIBRARY_CHANGED_EVENT, change) {
+        eprintln!("Failed to emit {} event: {}", LIBRARY_CHANGED_EVENT, e);
+    }
+}
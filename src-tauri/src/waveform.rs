@@ -0,0 +1,283 @@
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::analysis::{self, Biquad, DecodedAudio};
+use crate::db;
+use crate::duplicates;
+
+// Points across the whole track in the overview, whatever its length.
+const OVERVIEW_POINTS: usize = 1000;
+// Points per second in the zoomed-in detail level.
+const DETAIL_POINTS_PER_SECOND: f32 = 100.0;
+// Bump when the cached layout changes, so old entries are recomputed rather
+// than misread.
+const WAVEFORM_VERSION: u32 = 2;
+// Values stored per point: min, max, RMS and the low, mid and high band RMS.
+const POINT_VALUES: usize = 6;
+// Crossover frequencies between the bands: kicks and bass below the first,
+// hi-hats and cymbals above the second.
+const LOW_MID_CROSSOVER: f64 = 200.0;
+const MID_HIGH_CROSSOVER: f64 = 2000.0;
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WaveformResolution {
+    // The whole track at a fixed number of points, for the table and the
+    // player's scrub bar.
+    Overview,
+    // A fixed number of points per second, for zooming in.
+    Detail,
+}
+
+impl WaveformResolution {
+    const ALL: [WaveformResolution; 2] = [WaveformResolution::Overview, WaveformResolution::Detail];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            WaveformResolution::Overview => "overview",
+            WaveformResolution::Detail => "detail",
+        }
+    }
+
+    // Samples summarized by each point.
+    fn bucket_len(&self, frames: usize, sample_rate: u32) -> usize {
+        match self {
+            WaveformResolution::Overview => frames.div_ceil(OVERVIEW_POINTS),
+            WaveformResolution::Detail => (sample_rate as f32 / DETAIL_POINTS_PER_SECOND).round() as usize,
+        }
+        .max(1)
+    }
+}
+
+// Peak and RMS levels of the track mixed to mono, one entry per point, with
+// the RMS of its low, mid and high bands for coloring the waveform.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Waveform {
+    pub resolution: WaveformResolution,
+    pub duration: f32,
+    pub seconds_per_point: f32,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    pub rms: Vec<f32>,
+    pub low: Vec<f32>,
+    pub mid: Vec<f32>,
+    pub high: Vec<f32>,
+}
+
+// A point being summed up: its peaks, and the energy of the full signal and
+// of each band.
+struct Bucket {
+    len: usize,
+    min: f32,
+    max: f32,
+    power: [f64; 4],
+}
+
+impl Default for Bucket {
+    // Peaks start out of range so the first sample sets both, rather than a
+    // point that never crosses zero having a peak of 0.
+    fn default() -> Self {
+        Bucket { len: 0, min: f32::INFINITY, max: f32::NEG_INFINITY, power: [0.0; 4] }
+    }
+}
+
+// Splits a signal into three bands with 4th-order Linkwitz-Riley crossovers
+// (two Butterworth sections each).
+struct BandSplitter {
+    low: [Biquad; 2],
+    mid: [Biquad; 4],
+    high: [Biquad; 2],
+}
+
+impl BandSplitter {
+    fn new(sample_rate: u32) -> Self {
+        let low_pass = |cutoff| Biquad::low_pass(sample_rate, cutoff, BUTTERWORTH_Q);
+        let high_pass = |cutoff| Biquad::high_pass(sample_rate, cutoff, BUTTERWORTH_Q);
+        BandSplitter {
+            low: [low_pass(LOW_MID_CROSSOVER), low_pass(LOW_MID_CROSSOVER)],
+            mid: [
+                high_pass(LOW_MID_CROSSOVER),
+                high_pass(LOW_MID_CROSSOVER),
+                low_pass(MID_HIGH_CROSSOVER),
+                low_pass(MID_HIGH_CROSSOVER),
+            ],
+            high: [high_pass(MID_HIGH_CROSSOVER), high_pass(MID_HIGH_CROSSOVER)],
+        }
+    }
+
+    fn split(&mut self, sample: f64) -> [f64; 3] {
+        let cascade = |filters: &mut [Biquad]| filters.iter_mut().fold(sample, |value, filter| filter.process(value));
+        [cascade(&mut self.low), cascade(&mut self.mid), cascade(&mut self.high)]
+    }
+}
+
+impl Waveform {
+    fn new(resolution: WaveformResolution, duration: f32, seconds_per_point: f32) -> Self {
+        Waveform {
+            resolution,
+            duration,
+            seconds_per_point,
+            min: Vec::new(),
+            max: Vec::new(),
+            rms: Vec::new(),
+            low: Vec::new(),
+            mid: Vec::new(),
+            high: Vec::new(),
+        }
+    }
+
+    // Every resolution in one pass over the audio, so the band filters only
+    // run once.
+    pub fn compute_all(audio: &DecodedAudio) -> Vec<Self> {
+        let mono = audio.to_mono();
+        let sample_rate = audio.sample_rate.max(1);
+        let duration = mono.len() as f32 / sample_rate as f32;
+        let mut levels: Vec<(usize, Waveform, Bucket)> = WaveformResolution::ALL
+            .iter()
+            .map(|&resolution| {
+                let bucket = resolution.bucket_len(mono.len(), sample_rate);
+                let waveform = Waveform::new(resolution, duration, bucket as f32 / sample_rate as f32);
+                (bucket, waveform, Bucket::default())
+            })
+            .collect();
+
+        let mut splitter = BandSplitter::new(sample_rate);
+        for &sample in &mono {
+            let [low, mid, high] = splitter.split(sample as f64);
+            let full = sample as f64;
+            for (bucket_len, waveform, bucket) in levels.iter_mut() {
+                bucket.len += 1;
+                bucket.min = bucket.min.min(sample);
+                bucket.max = bucket.max.max(sample);
+                for (power, value) in bucket.power.iter_mut().zip([full, low, mid, high]) {
+                    *power += value * value;
+                }
+                if bucket.len == *bucket_len {
+                    waveform.push(std::mem::take(bucket));
+                }
+            }
+        }
+        levels
+            .into_iter()
+            .map(|(_, mut waveform, bucket)| {
+                if bucket.len > 0 {
+                    waveform.push(bucket);
+                }
+                waveform
+            })
+            .collect()
+    }
+
+    fn push(&mut self, bucket: Bucket) {
+        if bucket.len == 0 {
+            self.push_point([0.0; POINT_VALUES]);
+            return;
+        }
+        let [rms, low, mid, high] = bucket.power.map(|power| (power / bucket.len as f64).sqrt() as f32);
+        self.push_point([bucket.min, bucket.max, rms, low, mid, high]);
+    }
+
+    fn push_point(&mut self, [min, max, rms, low, mid, high]: [f32; POINT_VALUES]) {
+        self.min.push(min);
+        self.max.push(max);
+        self.rms.push(rms);
+        self.low.push(low);
+        self.mid.push(mid);
+        self.high.push(high);
+    }
+
+    // Duration and point spacing, then each point's values, as little-endian
+    // f32s.
+    fn encode(&self) -> Vec<u8> {
+        let mut values = vec![self.duration, self.seconds_per_point];
+        for i in 0..self.min.len() {
+            values.extend([self.min[i], self.max[i], self.rms[i], self.low[i], self.mid[i], self.high[i]]);
+        }
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn decode(bytes: &[u8], resolution: WaveformResolution) -> Option<Self> {
+        let values: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        let (header, points) = values.split_at_checked(2)?;
+        if points.len() % POINT_VALUES != 0 {
+            return None;
+        }
+        let mut waveform = Waveform::new(resolution, header[0], header[1]);
+        for point in points.chunks_exact(POINT_VALUES) {
+            waveform.push_point(point.try_into().ok()?);
+        }
+        Some(waveform)
+    }
+}
+
+// A file's size and modification time (in milliseconds since the epoch).
+fn file_stamp(path: &Path) -> Result<(i64, i64), String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_millis() as i64);
+    Ok((metadata.len() as i64, modified))
+}
+
+fn hash(path: &Path) -> Result<String, String> {
+    duplicates::hash_file(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+// The cached waveform, if the file hasn't changed since it was made. The file
+// is only hashed when its size or modification time no longer match.
+pub fn cached_waveform(beat_id: u32, path: &Path, resolution: WaveformResolution) -> Result<Option<Waveform>, String> {
+    let Some(cached) = db::get_cached_waveform(beat_id, resolution.as_str(), WAVEFORM_VERSION).map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+    let (file_size, file_modified) = file_stamp(path)?;
+    if cached.file_size != Some(file_size) || cached.file_modified != Some(file_modified) {
+        if hash(path)? != cached.content_hash {
+            return Ok(None);
+        }
+        db::set_waveform_file_stamp(beat_id, file_size, file_modified).map_err(|e| e.to_string())?;
+    }
+    Ok(Waveform::decode(&cached.data, resolution))
+}
+
+// Cache every resolution of a beat's waveform against its file as it is now.
+pub fn store_waveforms(beat_id: u32, path: &Path, waveforms: &[Waveform]) -> Result<(), String> {
+    let (file_size, file_modified) = file_stamp(path)?;
+    let content_hash = hash(path)?;
+    for waveform in waveforms {
+        let cached = db::CachedWaveform {
+            content_hash: content_hash.clone(),
+            file_size: Some(file_size),
+            file_modified: Some(file_modified),
+            data: waveform.encode(),
+        };
+        db::store_waveform(beat_id, waveform.resolution.as_str(), WAVEFORM_VERSION, &cached).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// A beat's waveform, from the cache while the file is unchanged. Otherwise
+// the file is decoded once and every resolution cached from it.
+pub fn get_waveform(beat_id: u32, resolution: WaveformResolution) -> Result<Waveform, String> {
+    let beat = db::get_beat(beat_id).map_err(|e| format!("Beat {} not found: {}", beat_id, e))?;
+    let path = Path::new(beat.file_path());
+    if let Some(waveform) = cached_waveform(beat_id, path, resolution)? {
+        return Ok(waveform);
+    }
+
+    println!("Generating waveform for beat {}: {}", beat_id, beat.file_path());
+    let audio = analysis::decode_file(path).map_err(|e| format!("Failed to decode {}: {}", beat.file_path(), e))?;
+    let waveforms = Waveform::compute_all(&audio);
+    store_waveforms(beat_id, path, &waveforms)?;
+    waveforms
+        .into_iter()
+        .find(|waveform| waveform.resolution == resolution)
+        .ok_or_else(|| "Unknown waveform resolution".to_string())
+}
@@ -1,5 +1,6 @@
 import { useState, useEffect, useCallback, useRef } from "react";
 import { invoke } from "@tauri-apps/api/tauri";
+import { listen } from "@tauri-apps/api/event";
 import { Beat } from "src/bindings";
 import { Play, Pause, SkipBack, SkipForward, Volume2 } from "lucide-react";
 
@@ -51,37 +52,46 @@ const BeatJockey: React.FC<BeatJockeyProps> = ({ playThisBeat }) => {
     }
   }, [playThisBeat, startPlayback, beatIndex, beatQueue]);
 
-  interface PlaybackStatus {
-    pos: number;
-    is_playing: boolean;
-  }
+  type PlaybackEvent =
+    | { kind: "started" }
+    | { kind: "position"; pos: number }
+    | { kind: "paused"; pos: number }
+    | { kind: "resumed"; pos: number }
+    | { kind: "seeked"; pos: number }
+    | { kind: "stopped" }
+    | { kind: "ended" }
+    | { kind: "error"; message: string };
 
   useEffect(() => {
-    const updatePlaybackStatus = async () => {
-      try {
-        const statusString = await invoke("get_playback_state");
-        const status = JSON.parse(statusString as string) as PlaybackStatus;
-
-        setPlaybackState((prevState) => {
-          // Only update state if there's a change
-          if (
-            prevState.currentTime !== status.pos ||
-            prevState.isPlaying !== status.is_playing
-          ) {
-            return {
-              currentTime: status.pos,
-              isPlaying: status.is_playing,
-            };
-          }
-          return prevState;
-        });
-      } catch (error) {
-        console.error("Error updating playback status:", error);
+    const unlisten = listen<PlaybackEvent>("playback", ({ payload }) => {
+      switch (payload.kind) {
+        case "started":
+          setPlaybackState({ currentTime: 0, isPlaying: true });
+          break;
+        case "position":
+          setPlaybackState({ currentTime: payload.pos, isPlaying: true });
+          break;
+        case "paused":
+          setPlaybackState({ currentTime: payload.pos, isPlaying: false });
+          break;
+        case "resumed":
+          setPlaybackState({ currentTime: payload.pos, isPlaying: true });
+          break;
+        case "seeked":
+          setPlaybackState((prev) => ({ ...prev, currentTime: payload.pos }));
+          break;
+        case "stopped":
+        case "ended":
+          setPlaybackState((prev) => ({ ...prev, isPlaying: false }));
+          break;
+        case "error":
+          console.error("Playback error:", payload.message);
+          break;
       }
+    });
+    return () => {
+      unlisten.then((stop) => stop());
     };
-
-    const interval = setInterval(updatePlaybackStatus, 1000);
-    return () => clearInterval(interval);
   }, []);
 
   const handleSliderChange = (value: number) => {
@@ -106,20 +116,18 @@ const BeatJockey: React.FC<BeatJockeyProps> = ({ playThisBeat }) => {
   };
 
   const handleNext = async () => {
-    if (beatIndex < beatQueue.length - 1) {
-      const nextIndex = beatIndex + 1;
-      setBeatIndex(nextIndex);
-      await startPlayback(beatQueue[nextIndex]);
+    try {
+      await invoke("skip_track");
+    } catch (error) {
+      console.error("Error skipping beat:", error);
     }
   };
 
   const handlePrev = async () => {
-    if (playbackState.currentTime > 3) {
-      await invoke("restart_beat");
-    } else if (beatIndex > 0) {
-      const prevIndex = beatIndex - 1;
-      setBeatIndex(prevIndex);
-      await startPlayback(beatQueue[prevIndex]);
+    try {
+      await invoke("previous_track");
+    } catch (error) {
+      console.error("Error going back:", error);
     }
   };
 